@@ -0,0 +1,117 @@
+//! `sellafield api`: every function the config script can call, with its
+//! signature and, for `set_*()` functions, the default.
+//!
+//! It's all generated from the real engine, so it can't drift from what
+//! scripts actually get: the signatures are whatever was registered and the
+//! defaults are the untouched config, by field name (`set_foo()` sets
+//! `foo`). Setters that don't map to a field like that just have no default.
+
+use anyhow::Result;
+use argh::FromArgs;
+
+use crate::{get_user_details, process::ProcessInfo, script_engine, Opts};
+
+#[derive(FromArgs)]
+/// List the config script's functions, their arguments and defaults.
+#[argh(subcommand, name = "api")]
+pub struct ApiOpts {}
+
+pub fn api(_opts: &ApiOpts) -> Result<()> {
+    for line in reference()? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// One line per function, sorted by name.
+fn reference() -> Result<Vec<String>> {
+    let uid = unsafe { libc::getuid() };
+    let opts = Opts {
+        uid,
+        ..Default::default()
+    };
+    let user_details = get_user_details(uid)?;
+    let (engine, _, config) = script_engine(&opts, "", "", &user_details, &ProcessInfo::default());
+    let defaults = serde_json::to_value(&*config.borrow())?;
+
+    let mut lines: Vec<String> = engine
+        .gen_fn_signatures(false)
+        .into_iter()
+        .map(|signature| {
+            let signature = tidy(&signature);
+            let name = signature.split('(').next().unwrap_or_default();
+            match name.strip_prefix("set_").and_then(|field| Some((field, defaults.get(field)?))) {
+                Some((field, default)) => format!("{}  [default: {}]", signature, format_default(field, default)),
+                None => signature,
+            }
+        })
+        .collect();
+    lines.sort();
+    Ok(lines)
+}
+
+/// Functions that can fail are registered as returning a `Result`, but
+/// scripts just see the value (or an exception).
+fn tidy(signature: &str) -> String {
+    const RESULT: &str = " -> core::result::Result<";
+    let (start, result) = match signature.split_once(RESULT) {
+        Some(split) => split,
+        None => return signature.to_owned(),
+    };
+    // The error type is the last parameter.
+    match result.rsplit_once(',') {
+        Some(("()", _)) => start.to_owned(),
+        Some((value, _)) => format!("{} -> {}", start, value),
+        None => signature.to_owned(),
+    }
+}
+
+/// Modes in octal, like they're written in scripts.
+fn format_default(field: &str, value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "none".to_owned(),
+        serde_json::Value::Number(n) if field.contains("permissions") || field.contains("umask") => {
+            format!("{:#o}", n.as_u64().unwrap_or_default())
+        }
+        serde_json::Value::String(s) if s.is_empty() => "\"\"".to_owned(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reference, tidy};
+
+    #[test]
+    fn test_reference() {
+        let reference = reference().unwrap();
+        let line = |name: &str| {
+            let prefix = format!("{}(", name);
+            reference.iter().find(|line| line.starts_with(&prefix)).cloned().unwrap_or_default()
+        };
+        assert!(line("set_permissions").ends_with("[default: 0o400]"), "{:?}", reference);
+        assert!(line("set_output_path").ends_with("[default: \"\"]"), "{:?}", reference);
+        assert!(line("set_max_size").ends_with("[default: none]"), "{:?}", reference);
+        assert!(line("home").starts_with("home() -> "), "{:?}", reference);
+        // Nothing from rhai's standard library.
+        assert!(line("len").is_empty());
+
+        let unit = "set_tag(_: string, _: string) -> core::result::Result<(),alloc::boxed::Box<EvalAltResult>>";
+        assert_eq!(tidy(unit), "set_tag(_: string, _: string)");
+        assert_eq!(tidy("f() -> core::result::Result<i64,Box<EvalAltResult>>"), "f() -> i64");
+        assert_eq!(tidy("home() -> string"), "home() -> string");
+    }
+
+    /// Every function scripts can call should be in the README.
+    #[test]
+    fn test_readme_covers_api() {
+        let readme = include_str!("../README.md");
+        let missing: Vec<String> = reference()
+            .unwrap()
+            .iter()
+            .map(|line| line.split('(').next().unwrap().to_owned())
+            .filter(|name| !readme.lines().any(|line| line.starts_with("* `") && line.contains(&format!("`{}(", name))))
+            .collect();
+        assert!(missing.is_empty(), "not documented in the README: {:?}", missing);
+    }
+}
@@ -0,0 +1,98 @@
+//! `sellafield bench`: time the write path with different buffer sizes, so
+//! there are real numbers to compare when changing it.
+
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use std::{io::Read, os::unix::io::AsRawFd, path::PathBuf, time::Instant};
+
+use crate::{drain, write_output_buffered, Config, Opts};
+
+#[derive(FromArgs)]
+/// Time writing a core of zeros with different copy buffer sizes. It doesn't
+/// need root.
+#[argh(subcommand, name = "bench")]
+pub struct BenchOpts {
+    /// size of the core in MB. Defaults to 1024.
+    #[argh(option, default = "1024")]
+    size_mb: u64,
+
+    /// buffer size to try in KB. Can be repeated. Defaults to 4, 64 and 1024.
+    #[argh(option)]
+    buffer_kb: Vec<usize>,
+
+    /// directory to write to. Defaults to the system temporary directory. Use
+    /// the real crash directory for realistic numbers.
+    #[argh(option)]
+    dir: Option<PathBuf>,
+
+    /// time draining a skipped core from a pipe instead, with splice() and
+    /// with a read loop.
+    #[argh(switch)]
+    drain: bool,
+}
+
+pub fn bench(opts: &BenchOpts) -> Result<()> {
+    if opts.drain {
+        return bench_drain(opts.size_mb * 1_000_000);
+    }
+    let dir = opts.dir.clone().unwrap_or_else(std::env::temp_dir);
+    let output = dir.join(format!("sellafield-bench.{}", std::process::id()));
+    let buffer_kb = if opts.buffer_kb.is_empty() { vec![4, 64, 1024] } else { opts.buffer_kb.clone() };
+    let size = opts.size_mb * 1_000_000;
+
+    let config = Config {
+        output_path: output.to_string_lossy().into_owned(),
+        permissions: 0o600,
+        ..Default::default()
+    };
+    let crash = Opts {
+        core_limit: u64::MAX,
+        ..Default::default()
+    };
+
+    for kb in buffer_kb {
+        let mut input = std::io::repeat(0).take(size);
+        let start = Instant::now();
+        let result = write_output_buffered(&config, &crash, &mut input, kb * 1024);
+        // Include the time to get it on disk, otherwise it's mostly measuring
+        // the page cache.
+        let result = result.and_then(|_| Ok(fs::File::open(&output)?.sync_all()?));
+        let elapsed = start.elapsed();
+        let _ = fs::remove_file(&output);
+        result.with_context(|| format!("error writing {}", output.display()))?;
+        println!(
+            "{:>6} KB buffer: {} MB in {:.2} s, {:.0} MB/s",
+            kb,
+            opts.size_mb,
+            elapsed.as_secs_f64(),
+            size as f64 / 1e6 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+fn bench_drain(size: u64) -> Result<()> {
+    type Drain = fn(&std::fs::File) -> std::io::Result<u64>;
+    let methods: [(&str, Drain); 2] = [
+        ("splice", |pipe| Ok(drain::splice_to_null(pipe.as_raw_fd(), u64::MAX)?.unwrap_or(0))),
+        ("read loop", |mut pipe| drain::read_and_discard(&mut pipe, u64::MAX)),
+    ];
+    for (name, method) in methods {
+        let pipe = drain::zeros_pipe(size)?;
+        let start = Instant::now();
+        let drained = method(&pipe)?;
+        let elapsed = start.elapsed();
+        if drained != size {
+            bail!("{} only drained {} of {} bytes", name, drained, size);
+        }
+        println!(
+            "{:>10}: {} MB in {:.2} s, {:.0} MB/s",
+            name,
+            size / 1_000_000,
+            elapsed.as_secs_f64(),
+            size as f64 / 1e6 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! `sellafield cat`: undo whatever compression and encryption a stored core
+//! has, as worked out from its extensions, using the usual command line tools.
+
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+};
+
+#[derive(FromArgs)]
+/// Write a plain core to stdout, decompressing (.gz, .zst, .xz) and
+/// decrypting (.age) it as its extensions say.
+#[argh(subcommand, name = "cat")]
+pub struct CatOpts {
+    /// path to the core.
+    #[argh(positional)]
+    core: PathBuf,
+
+    /// age identity file for decrypting .age cores.
+    #[argh(option)]
+    identity: Option<PathBuf>,
+
+    /// file to write the core to instead of stdout.
+    #[argh(option)]
+    output: Option<PathBuf>,
+}
+
+/// The command to undo each extension. `.truncated` is just a marker.
+const DECODERS: &[(&str, &[&str])] = &[
+    (".gz", &["gzip", "-dc"]),
+    (".zst", &["zstd", "-dc"]),
+    (".xz", &["xz", "-dc"]),
+    (".age", &["age", "-d"]),
+    (".truncated", &[]),
+];
+
+/// The decoding commands for `name`, in the order they need to run
+/// (outermost extension first).
+fn decoders(name: &str, identity: Option<&Path>) -> Result<Vec<Command>> {
+    let mut name = name;
+    let mut commands = Vec::new();
+    while let Some((stripped, extension, args)) = DECODERS
+        .iter()
+        .find_map(|(extension, args)| name.strip_suffix(extension).map(|stripped| (stripped, *extension, *args)))
+    {
+        name = stripped;
+        let Some((program, args)) = args.split_first() else {
+            continue;
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        if extension == ".age" {
+            match identity {
+                Some(identity) => command.arg("-i").arg(identity),
+                None => bail!("the core is age encrypted; pass --identity"),
+            };
+        }
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+pub fn cat(opts: &CatOpts) -> Result<()> {
+    let name = opts.core.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let commands = decoders(&name, opts.identity.as_deref())?;
+    let input = fs::File::open(&opts.core)?;
+
+    let output = match &opts.output {
+        Some(output) => Some(fs::File::create(output)?),
+        None => None,
+    };
+    if commands.is_empty() {
+        let mut input = input;
+        match output {
+            Some(mut output) => io::copy(&mut input, &mut output)?,
+            None => io::copy(&mut input, &mut io::stdout().lock())?,
+        };
+        return Ok(());
+    }
+
+    // Chain them together like a shell pipeline.
+    let mut children: Vec<(String, Child)> = Vec::new();
+    let mut stdin = Stdio::from(input.into_parts().0);
+    let last = commands.len() - 1;
+    for (i, mut command) in commands.into_iter().enumerate() {
+        let stdout = match (i == last, &output) {
+            (false, _) => Stdio::piped(),
+            (true, Some(output)) => Stdio::from(output.file().try_clone()?),
+            (true, None) => Stdio::inherit(),
+        };
+        let program = command.get_program().to_string_lossy().to_string();
+        let mut child = command
+            .stdin(stdin)
+            .stdout(stdout)
+            .spawn()
+            .with_context(|| format!("error running {}; is it installed?", program))?;
+        stdin = match child.stdout.take() {
+            Some(stdout) => Stdio::from(stdout),
+            None => Stdio::null(),
+        };
+        children.push((program, child));
+    }
+    for (program, mut child) in children {
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} failed ({})", program, status);
+        }
+    }
+    io::stdout().flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cat, decoders, CatOpts};
+    use std::path::Path;
+
+    #[test]
+    fn test_decoders() {
+        let programs = |name: &str| {
+            decoders(name, Some(Path::new("key.txt")))
+                .unwrap()
+                .iter()
+                .map(|command| command.get_program().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(programs("core.1.foo.zst.age"), vec!["age", "zstd"]);
+        assert_eq!(programs("core.1.foo.gz.truncated"), vec!["gzip"]);
+        assert_eq!(programs("core.1.agent"), Vec::<String>::new());
+        assert!(decoders("core.age", None).is_err());
+    }
+
+    #[test]
+    fn test_cat() {
+        if std::process::Command::new("gzip").arg("--version").output().is_err() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let core = dir.path().join("core.1.foo");
+        std::fs::write(&core, "0123456789").unwrap();
+        assert!(std::process::Command::new("gzip").arg(&core).status().unwrap().success());
+
+        let output = dir.path().join("plain");
+        cat(&CatOpts {
+            core: dir.path().join("core.1.foo.gz"),
+            identity: None,
+            output: Some(output.clone()),
+        })
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "0123456789");
+    }
+}
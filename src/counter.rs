@@ -0,0 +1,128 @@
+//! `--max-total-dumps`: a circuit breaker that stops writing cores after a
+//! set number until someone runs `sellafield reset-counter`.
+//!
+//! The count is a `StateFile` in the lock directory, which is on tmpfs
+//! (`/run`) by default so it also resets at boot. Each core is counted
+//! before it's written, in the same locked update as the check, so crashes
+//! at the same time can't all get in under the limit; the count is given
+//! back if the core isn't written after all.
+
+use anyhow::Result;
+use argh::FromArgs;
+use std::path::{Path, PathBuf};
+
+use crate::{state::StateFile, DEFAULT_LOCK_DIR};
+
+const COUNTER_FILE: &str = "total_dumps";
+
+/// The open counter file.
+#[derive(Debug)]
+pub struct Counter {
+    state: StateFile,
+}
+
+pub enum Reserve {
+    /// The limit has been reached; there are this many already.
+    Full(u64),
+    Reserved(Reservation),
+}
+
+/// A core that has been counted but not written yet. Dropping it takes it
+/// off the count again unless it's been kept.
+#[derive(Debug)]
+pub struct Reservation {
+    counter: Counter,
+    total: u64,
+    kept: bool,
+}
+
+impl Counter {
+    pub fn open(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            state: StateFile::open(dir, COUNTER_FILE)?,
+        })
+    }
+
+    /// Count a core if there have been fewer than `max`.
+    pub fn reserve(mut self, max: u64) -> Result<Reserve> {
+        // Missing or corrupt just means zero.
+        let total = self.state.update(|count: &mut u64| {
+            if *count >= max {
+                return Err(*count);
+            }
+            *count += 1;
+            Ok(*count)
+        })?;
+        Ok(match total {
+            Ok(total) => Reserve::Reserved(Reservation {
+                counter: self,
+                total,
+                kept: false,
+            }),
+            Err(total) => Reserve::Full(total),
+        })
+    }
+}
+
+impl Reservation {
+    /// The core was written. Returns the new total.
+    pub fn keep(mut self) -> u64 {
+        self.kept = true;
+        self.total
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.kept {
+            if let Err(e) = self.counter.state.update(|count: &mut u64| *count = count.saturating_sub(1)) {
+                crate::warn(&format!("error uncounting a core that wasn't written: {:#}", e));
+            }
+        }
+    }
+}
+
+#[derive(FromArgs)]
+/// Reset the --max-total-dumps counter so cores are written again.
+#[argh(subcommand, name = "reset-counter")]
+pub struct ResetCounterOpts {
+    /// the --lock-dir sellafield is run with. Defaults to /run/sellafield.
+    #[argh(option, default = "PathBuf::from(DEFAULT_LOCK_DIR)")]
+    lock_dir: PathBuf,
+}
+
+pub fn reset(opts: &ResetCounterOpts) -> Result<()> {
+    println!("Reset the counter from {} cores", reset_dir(&opts.lock_dir)?);
+    Ok(())
+}
+
+/// Reset the counter in `dir`, returning what it was.
+pub fn reset_dir(dir: &Path) -> Result<u64> {
+    Counter::open(dir)?.state.update(|count: &mut u64| std::mem::take(count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reset_dir, Counter, Reserve};
+
+    #[test]
+    fn test_counter() {
+        let dir = tempfile::tempdir().unwrap();
+        let reserve = || match Counter::open(dir.path()).unwrap().reserve(2).unwrap() {
+            Reserve::Reserved(reservation) => Ok(reservation),
+            Reserve::Full(total) => Err(total),
+        };
+        assert_eq!(reserve().unwrap().keep(), 1);
+        // Both at once, so the second doesn't fit until the first is given back.
+        let second = reserve().unwrap();
+        assert_eq!(reserve().unwrap_err(), 2);
+        drop(second);
+        assert_eq!(reserve().unwrap().keep(), 2);
+        assert_eq!(reserve().unwrap_err(), 2);
+
+        assert_eq!(reset_dir(dir.path()).unwrap(), 2);
+        assert_eq!(reserve().unwrap().keep(), 1);
+        // The file is just the number, like it always was.
+        assert_eq!(std::fs::read_to_string(dir.path().join(super::COUNTER_FILE)).unwrap(), "1");
+    }
+}
@@ -0,0 +1,65 @@
+//! `set_debounce_secs()`: skip cores from a crash we've captured recently,
+//! so a crash loop gives one core a minute rather than hundreds.
+//!
+//! The last time each crash was captured is kept in a small JSON file in the
+//! lock directory, like the sampling counts. Crashes are identified by the
+//! executable path and its build ID if it has one.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use crate::{elf::hex, state::StateFile};
+
+pub const STATE_FILE: &str = "debounce.json";
+
+/// Identify a crash by its executable and build ID.
+pub fn key(full_exe: &str, build_id: Option<&[u8]>) -> String {
+    match build_id {
+        Some(build_id) => format!("{}:{}", full_exe, hex(build_id)),
+        None => full_exe.to_owned(),
+    }
+}
+
+/// Whether `key` was captured less than `secs` before `now`. Corrupt state
+/// just means nothing has been seen.
+pub fn recently_captured(state: &mut StateFile, key: &str, now: u64, secs: u64) -> Result<bool> {
+    state.update(|times: &mut BTreeMap<String, u64>| {
+        times.get(key).is_some_and(|&last| now < last.saturating_add(secs))
+    })
+}
+
+/// Record that `key` was captured at `now`, forgetting anything too old to
+/// matter.
+pub fn record(state: &mut StateFile, key: &str, now: u64, secs: u64) -> Result<()> {
+    state.update(|times: &mut BTreeMap<String, u64>| {
+        times.retain(|_, last| now < last.saturating_add(secs));
+        times.insert(key.to_owned(), now);
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key, recently_captured, record, STATE_FILE};
+    use crate::state::StateFile;
+
+    #[test]
+    fn test_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = StateFile::open(dir.path(), STATE_FILE).unwrap();
+        let foo = key("/usr/bin/foo", Some(&[0x12, 0x34]));
+        let rebuilt = key("/usr/bin/foo", Some(&[0x56, 0x78]));
+        assert_eq!(foo, "/usr/bin/foo:1234");
+
+        assert!(!recently_captured(&mut state, &foo, 1000, 60).unwrap());
+        record(&mut state, &foo, 1000, 60).unwrap();
+        assert!(recently_captured(&mut state, &foo, 1059, 60).unwrap());
+        assert!(!recently_captured(&mut state, &foo, 1060, 60).unwrap());
+        // A different build is a different crash.
+        assert!(!recently_captured(&mut state, &rebuilt, 1001, 60).unwrap());
+
+        // Old entries are dropped.
+        record(&mut state, &rebuilt, 2000, 60).unwrap();
+        let saved = std::fs::read_to_string(dir.path().join(STATE_FILE)).unwrap();
+        assert!(!saved.contains(&foo), "{}", saved);
+    }
+}
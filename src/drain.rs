@@ -0,0 +1,126 @@
+//! Throwing away the rest of a core we aren't keeping.
+//!
+//! With stdin being the kernel's pipe, `splice()`ing it to `/dev/null` moves
+//! the pages around inside the kernel without copying them to us at all. If
+//! that isn't possible (stdin isn't a pipe, or the kernel can't splice to
+//! `/dev/null`) it falls back to reading into a buffer and ignoring it.
+
+use std::{
+    io::{self, Read},
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+/// Most bytes to ask `splice()` for at once. It does less if it wants to.
+const SPLICE_CHUNK: usize = 1 << 30;
+
+/// Read and discard up to `limit` bytes from `fd`, returning how many there
+/// were.
+pub fn drain(fd: RawFd, limit: u64) -> io::Result<u64> {
+    match splice_to_null(fd, limit) {
+        Ok(Some(bytes)) => Ok(bytes),
+        Ok(None) => read_and_discard(&mut Fd(fd), limit),
+        Err(e) => Err(e),
+    }
+}
+
+/// `None` if `fd` can't be spliced, before anything has been read.
+pub fn splice_to_null(fd: RawFd, limit: u64) -> io::Result<Option<u64>> {
+    let null = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+    let mut total = 0;
+    while total < limit {
+        let len = (limit - total).min(SPLICE_CHUNK as u64) as usize;
+        let rc = unsafe { libc::splice(fd, std::ptr::null_mut(), null.as_raw_fd(), std::ptr::null_mut(), len, 0) };
+        if rc == 0 {
+            break;
+        }
+        if rc < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL) if total == 0 => return Ok(None),
+                _ => return Err(error),
+            }
+        }
+        total += rc as u64;
+    }
+    Ok(Some(total))
+}
+
+/// The simple way, with one buffer on the stack.
+pub fn read_and_discard(input: &mut impl Read, limit: u64) -> io::Result<u64> {
+    let mut buffer = [0; 64 * 1024];
+    let mut total = 0;
+    while total < limit {
+        let len = (limit - total).min(buffer.len() as u64) as usize;
+        match input.read(&mut buffer[..len]) {
+            Ok(0) => break,
+            Ok(bytes) => total += bytes as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+/// A borrowed fd that can be read from without closing it afterwards.
+struct Fd(RawFd);
+
+impl Read for Fd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rc = unsafe { libc::read(self.0, buf.as_mut_ptr().cast(), buf.len()) };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(rc as usize)
+    }
+}
+
+/// A pipe with `bytes` of zeros being written into it by another thread, like
+/// a core from the kernel. Returns the read end; the thread closes the write
+/// end when it's done.
+pub fn zeros_pipe(bytes: u64) -> io::Result<std::fs::File> {
+    use std::{io::Write, os::unix::io::FromRawFd};
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let read_end = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+    let mut write_end = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+    std::thread::spawn(move || {
+        let buffer = [0; 64 * 1024];
+        let mut left = bytes;
+        while left > 0 {
+            let len = left.min(buffer.len() as u64) as usize;
+            if write_end.write_all(&buffer[..len]).is_err() {
+                return;
+            }
+            left -= len as u64;
+        }
+    });
+    Ok(read_end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{drain, read_and_discard, splice_to_null, zeros_pipe};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_drain() {
+        let pipe = zeros_pipe(10_000_000).unwrap();
+        assert_eq!(drain(pipe.as_raw_fd(), u64::MAX).unwrap(), 10_000_000);
+
+        let pipe = zeros_pipe(10_000_000).unwrap();
+        assert_eq!(splice_to_null(pipe.as_raw_fd(), 1_000_000).unwrap(), Some(1_000_000));
+        assert_eq!(read_and_discard(&mut &pipe, u64::MAX).unwrap(), 9_000_000);
+
+        // Files can't be spliced to /dev/null.
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, &[1; 1000]).unwrap();
+        std::io::Seek::rewind(&mut file).unwrap();
+        assert_eq!(splice_to_null(file.as_raw_fd(), u64::MAX).unwrap(), None);
+        assert_eq!(drain(file.as_raw_fd(), 100).unwrap(), 100);
+        assert_eq!(drain(file.as_raw_fd(), u64::MAX).unwrap(), 900);
+    }
+}
@@ -0,0 +1,529 @@
+//! Just enough ELF parsing to get notes out of a core.
+//!
+//! Cores are often truncated (by `ulimit -c` or `set_max_size()`) so
+//! anything that runs off the end of the file is treated as missing rather
+//! than an error.
+//!
+//! Both 32 and 64-bit little-endian ELF are understood, since a 32-bit
+//! process on a 64-bit kernel gets a 32-bit core.
+
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+pub const NT_GNU_BUILD_ID: u32 = 3;
+/// "SIGI"
+pub const NT_SIGINFO: u32 = 0x53494749;
+
+/// Signals that fill in `si_addr` with the faulting address.
+const FAULT_SIGNALS: &[i32] = &[libc::SIGILL, libc::SIGFPE, libc::SIGSEGV, libc::SIGBUS, libc::SIGTRAP];
+
+/// Don't read silly amounts of data if the headers are corrupt.
+const MAX_NOTES_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(FromArgs)]
+/// Print the GNU build ID of the executable that dumped a core, for fetching
+/// the matching debuginfo.
+#[argh(subcommand, name = "build-id")]
+pub struct BuildIdOpts {
+    /// path to the core.
+    #[argh(positional)]
+    core: PathBuf,
+}
+
+pub fn print_build_id(opts: &BuildIdOpts) -> Result<()> {
+    let mut file = BufReader::new(fs::File::open(&opts.core)?);
+    match build_id(&mut file)? {
+        Some(build_id) => println!("{}", hex(&build_id)),
+        None => bail!("build ID note not found in {} (it may be truncated)", opts.core.display()),
+    }
+    Ok(())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub name: Vec<u8>,
+    pub note_type: u32,
+    pub desc: Vec<u8>,
+}
+
+/// The interesting parts of the `siginfo_t` in a core's `NT_SIGINFO` note.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Siginfo {
+    pub signo: i32,
+    /// `si_code`, e.g. `SEGV_MAPERR` (1) or `SEGV_ACCERR` (2) for `SIGSEGV`.
+    pub code: i32,
+    /// `si_addr` in hex, for signals that have one. It's a string because
+    /// JSON numbers can't hold all 64-bit addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault_address: Option<String>,
+}
+
+/// Whether an ELF file is 32 or 64-bit, which changes the header layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Elf32,
+    Elf64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    align: u64,
+}
+
+/// Find the build ID of the main executable in a core.
+///
+/// Cores don't usually have the build ID in their own notes, but the kernel
+/// dumps the first page of every ELF mapping, which includes its program
+/// headers and (nearly always) its build ID note. The executable is normally
+/// mapped at the lowest address so we use the first one that has a build ID.
+pub fn build_id<R: Read + Seek>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let headers = match read_program_headers(r, 0)? {
+        Some((_, headers)) => headers,
+        None => return Ok(None),
+    };
+
+    for note in read_notes(r, 0, &headers)? {
+        if note.name == b"GNU" && note.note_type == NT_GNU_BUILD_ID {
+            return Ok(Some(note.desc));
+        }
+    }
+
+    let mut loads: Vec<_> = headers.iter().filter(|h| h.p_type == PT_LOAD && h.filesz > 0).collect();
+    loads.sort_by_key(|h| h.vaddr);
+    for load in loads {
+        let mapped = match read_program_headers(r, load.offset)? {
+            Some((_, mapped)) => mapped,
+            None => continue,
+        };
+        // The mapped ELF's offsets are relative to the start of the mapping,
+        // and only the part inside this segment was dumped.
+        let mapped: Vec<_> = mapped
+            .into_iter()
+            .filter(|h| h.offset.saturating_add(h.filesz) <= load.filesz)
+            .collect();
+        for note in read_notes(r, load.offset, &mapped)? {
+            if note.name == b"GNU" && note.note_type == NT_GNU_BUILD_ID {
+                return Ok(Some(note.desc));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Get the signal details from the start of a core. The kernel writes its own
+/// notes straight after the program headers, so `prefix` doesn't need to be
+/// the whole core; `None` if the note isn't in it.
+pub fn siginfo(prefix: &[u8]) -> Option<Siginfo> {
+    let (class, headers) = read_program_headers(&mut Cursor::new(prefix), 0).ok()??;
+    // Parse as much of the segment as we have; truncated notes are dropped.
+    let header = headers.iter().find(|h| h.p_type == PT_NOTE)?;
+    let start = usize::try_from(header.offset).ok()?.min(prefix.len());
+    let end = usize::try_from(header.offset.saturating_add(header.filesz)).unwrap_or(usize::MAX).min(prefix.len());
+    let note = parse_notes(&prefix[start..end], 4)
+        .into_iter()
+        .find(|note| note.name == b"CORE" && note.note_type == NT_SIGINFO)?;
+    // siginfo_t starts with si_signo, si_errno and si_code, and si_addr is the
+    // first thing in the union after them, aligned to the pointer size.
+    let (addr_offset, min_len) = match class {
+        Class::Elf32 => (12, 16),
+        Class::Elf64 => (16, 24),
+    };
+    if note.desc.len() < min_len {
+        return None;
+    }
+    let signo = u32_at(&note.desc, 0) as i32;
+    let fault_address = match class {
+        Class::Elf32 => u32_at(&note.desc, addr_offset) as u64,
+        Class::Elf64 => u64_at(&note.desc, addr_offset),
+    };
+    Some(Siginfo {
+        signo,
+        code: u32_at(&note.desc, 8) as i32,
+        fault_address: FAULT_SIGNALS.contains(&signo).then(|| format!("{:#x}", fault_address)),
+    })
+}
+
+/// The architecture of the process that dumped a core, from its ELF header
+/// `e_machine` and class, with the names `uname -m` uses, e.g. `x86_64`, or
+/// `i386` for a 32-bit x86 process. `None` if it isn't an ELF file we
+/// understand.
+pub fn arch(prefix: &[u8]) -> Option<String> {
+    let class = elf_class(prefix)?;
+    let machine = u16_at(prefix, 0x12);
+    let name = match (machine, class) {
+        (3, _) => "i386",
+        (62, Class::Elf64) => "x86_64",
+        (62, Class::Elf32) => "x32",
+        (40, _) => "arm",
+        (183, _) => "aarch64",
+        (243, Class::Elf64) => "riscv64",
+        (243, Class::Elf32) => "riscv32",
+        (8, Class::Elf64) => "mips64",
+        (8, Class::Elf32) => "mips",
+        (21, _) => "ppc64le",
+        (20, _) => "ppcle",
+        (22, Class::Elf64) => "s390x",
+        (22, Class::Elf32) => "s390",
+        (258, _) => "loongarch64",
+        (machine, Class::Elf64) => return Some(format!("elf64-machine-{}", machine)),
+        (machine, Class::Elf32) => return Some(format!("elf32-machine-{}", machine)),
+    };
+    Some(name.to_owned())
+}
+
+/// The class of the little-endian ELF header at the start of `header`.
+fn elf_class(header: &[u8]) -> Option<Class> {
+    if header.len() < 0x34 || &header[0..4] != ELF_MAGIC || header[5] != ELFDATA2LSB {
+        return None;
+    }
+    match header[4] {
+        ELFCLASS32 => Some(Class::Elf32),
+        ELFCLASS64 => Some(Class::Elf64),
+        _ => None,
+    }
+}
+
+/// Read the program headers of the ELF file starting at `base`. Returns
+/// `None` if there isn't one there (or it's truncated).
+fn read_program_headers<R: Read + Seek>(r: &mut R, base: u64) -> Result<Option<(Class, Vec<ProgramHeader>)>> {
+    // The 32-bit header is only 52 bytes, but something always follows it.
+    let mut ident = [0u8; 64];
+    if !read_at(r, base, &mut ident)? {
+        return Ok(None);
+    }
+    let class = match elf_class(&ident) {
+        Some(class) => class,
+        None => return Ok(None),
+    };
+    let (phoff, phentsize, phnum, min_phentsize) = match class {
+        Class::Elf32 => (u32_at(&ident, 0x1c) as u64, u16_at(&ident, 0x2a), u16_at(&ident, 0x2c), 32),
+        Class::Elf64 => (u64_at(&ident, 0x20), u16_at(&ident, 0x36), u16_at(&ident, 0x38), 56),
+    };
+    if phentsize < min_phentsize {
+        return Ok(None);
+    }
+
+    let size = phentsize as u64 * phnum as u64;
+    if size > MAX_NOTES_SIZE {
+        return Ok(None);
+    }
+    let mut table = vec![0u8; size as usize];
+    if !read_at(r, base.saturating_add(phoff), &mut table)? {
+        return Ok(None);
+    }
+
+    let headers = table
+        .chunks_exact(phentsize as usize)
+        .map(|ph| match class {
+            Class::Elf32 => ProgramHeader {
+                p_type: u32_at(ph, 0),
+                offset: u32_at(ph, 4) as u64,
+                vaddr: u32_at(ph, 8) as u64,
+                filesz: u32_at(ph, 16) as u64,
+                align: u32_at(ph, 28) as u64,
+            },
+            Class::Elf64 => ProgramHeader {
+                p_type: u32_at(ph, 0),
+                offset: u64_at(ph, 8),
+                vaddr: u64_at(ph, 16),
+                filesz: u64_at(ph, 32),
+                align: u64_at(ph, 48),
+            },
+        })
+        .collect();
+    Ok(Some((class, headers)))
+}
+
+/// Read all the notes from the `PT_NOTE` segments in `headers`. Segments that
+/// are past the end of the file are skipped.
+fn read_notes<R: Read + Seek>(r: &mut R, base: u64, headers: &[ProgramHeader]) -> Result<Vec<Note>> {
+    let mut notes = Vec::new();
+    for header in headers.iter().filter(|h| h.p_type == PT_NOTE) {
+        if header.filesz > MAX_NOTES_SIZE {
+            continue;
+        }
+        let mut data = vec![0u8; header.filesz as usize];
+        if !read_at(r, base.saturating_add(header.offset), &mut data)? {
+            continue;
+        }
+        let align = if header.align == 8 { 8 } else { 4 };
+        notes.extend(parse_notes(&data, align));
+    }
+    Ok(notes)
+}
+
+/// Parse a note segment. Stops at the first malformed note.
+pub fn parse_notes(data: &[u8], align: usize) -> Vec<Note> {
+    let mut notes = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        let namesz = u32_at(data, pos) as usize;
+        let descsz = u32_at(data, pos + 4) as usize;
+        let note_type = u32_at(data, pos + 8);
+
+        let name_start = pos + 12;
+        let desc_start = match name_start.checked_add(namesz) {
+            Some(end) => align_up(end, align),
+            None => break,
+        };
+        let desc_end = match desc_start.checked_add(descsz) {
+            Some(end) if end <= data.len() => end,
+            _ => break,
+        };
+
+        // The name includes a NUL terminator.
+        let name = &data[name_start..name_start + namesz];
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+
+        notes.push(Note {
+            name: name.to_vec(),
+            note_type,
+            desc: data[desc_start..desc_end].to_vec(),
+        });
+        pos = align_up(desc_end, align);
+    }
+    notes
+}
+
+fn align_up(x: usize, align: usize) -> usize {
+    x.div_ceil(align) * align
+}
+
+/// Fill `buf` from `offset`. Returns false if the file isn't long enough.
+fn read_at<R: Read + Seek>(r: &mut R, offset: u64, buf: &mut [u8]) -> Result<bool> {
+    r.seek(SeekFrom::Start(offset))?;
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{
+        arch, build_id, parse_notes, read_program_headers, siginfo, Note, Siginfo, NT_GNU_BUILD_ID, NT_SIGINFO, PT_LOAD,
+        PT_NOTE,
+    };
+    use std::io::Cursor;
+
+    pub fn note(name: &[u8], note_type: u32, desc: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend((name.len() as u32 + 1).to_le_bytes());
+        out.extend((desc.len() as u32).to_le_bytes());
+        out.extend(note_type.to_le_bytes());
+        out.extend(name);
+        out.push(0);
+        while out.len() % 4 != 0 {
+            out.push(0);
+        }
+        out.extend(desc);
+        while out.len() % 4 != 0 {
+            out.push(0);
+        }
+        out
+    }
+
+    /// Build a 64-bit ELF with one segment per `(p_type, vaddr, data)`.
+    pub fn elf(segments: &[(u32, u64, Vec<u8>)]) -> Vec<u8> {
+        let phoff = 64u64;
+        let data_start = phoff + 56 * segments.len() as u64;
+
+        let mut out = vec![0u8; 64];
+        out[0..4].copy_from_slice(b"\x7fELF");
+        out[4] = 2;
+        out[5] = 1;
+        out[6] = 1;
+        out[16..18].copy_from_slice(&4u16.to_le_bytes()); // ET_CORE
+        out[0x20..0x28].copy_from_slice(&phoff.to_le_bytes());
+        out[0x34..0x36].copy_from_slice(&64u16.to_le_bytes());
+        out[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        out[0x38..0x3a].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = data_start;
+        for (p_type, vaddr, data) in segments {
+            out.extend(p_type.to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+            out.extend(vaddr.to_le_bytes());
+            out.extend(0u64.to_le_bytes());
+            out.extend((data.len() as u64).to_le_bytes());
+            out.extend((data.len() as u64).to_le_bytes());
+            out.extend(4u64.to_le_bytes());
+            offset += data.len() as u64;
+        }
+        for (_, _, data) in segments {
+            out.extend(data);
+        }
+        out
+    }
+
+    /// Build a 32-bit ELF like `elf()`, for an i386 process.
+    pub fn elf32(segments: &[(u32, u64, Vec<u8>)]) -> Vec<u8> {
+        let phoff = 52u32;
+        let data_start = phoff + 32 * segments.len() as u32;
+
+        let mut out = vec![0u8; 52];
+        out[0..4].copy_from_slice(b"\x7fELF");
+        out[4] = 1;
+        out[5] = 1;
+        out[6] = 1;
+        out[16..18].copy_from_slice(&4u16.to_le_bytes()); // ET_CORE
+        out[18..20].copy_from_slice(&3u16.to_le_bytes()); // EM_386
+        out[0x1c..0x20].copy_from_slice(&phoff.to_le_bytes());
+        out[0x28..0x2a].copy_from_slice(&52u16.to_le_bytes());
+        out[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+        out[0x2c..0x2e].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = data_start;
+        for (p_type, vaddr, data) in segments {
+            out.extend(p_type.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+            out.extend((*vaddr as u32).to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend((data.len() as u32).to_le_bytes());
+            out.extend((data.len() as u32).to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend(4u32.to_le_bytes());
+            offset += data.len() as u32;
+        }
+        for (_, _, data) in segments {
+            out.extend(data);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_notes() {
+        let mut data = note(b"CORE", 1, &[1, 2, 3, 4, 5]);
+        data.extend(note(b"GNU", NT_GNU_BUILD_ID, &[0xab; 20]));
+        let notes = parse_notes(&data, 4);
+        assert_eq!(
+            notes,
+            [
+                Note { name: b"CORE".to_vec(), note_type: 1, desc: vec![1, 2, 3, 4, 5] },
+                Note { name: b"GNU".to_vec(), note_type: NT_GNU_BUILD_ID, desc: vec![0xab; 20] },
+            ]
+        );
+        // Truncated.
+        assert_eq!(parse_notes(&data[..data.len() - 1], 4).len(), 1);
+    }
+
+    #[test]
+    fn test_build_id_in_mapped_exe() {
+        let exe = elf(&[(PT_NOTE, 0, note(b"GNU", NT_GNU_BUILD_ID, &[0x12, 0x34]))]);
+        let lib = elf(&[(PT_NOTE, 0, note(b"GNU", NT_GNU_BUILD_ID, &[0x56, 0x78]))]);
+        let core = elf(&[
+            (PT_NOTE, 0, note(b"CORE", 1, &[0; 8])),
+            (PT_LOAD, 0x7f0000, lib),
+            (PT_LOAD, 0x400000, exe),
+        ]);
+        assert_eq!(build_id(&mut Cursor::new(&core)).unwrap(), Some(vec![0x12, 0x34]));
+
+        // Truncated before the mappings.
+        assert_eq!(build_id(&mut Cursor::new(&core[..200])).unwrap(), None);
+        // Not an ELF file at all.
+        assert_eq!(build_id(&mut Cursor::new(b"hello")).unwrap(), None);
+
+        // A 32-bit process.
+        let exe = elf32(&[(PT_NOTE, 0, note(b"GNU", NT_GNU_BUILD_ID, &[0x9a, 0xbc]))]);
+        let core = elf32(&[(PT_NOTE, 0, note(b"CORE", 1, &[0; 8])), (PT_LOAD, 0x8048000, exe)]);
+        assert_eq!(build_id(&mut Cursor::new(&core)).unwrap(), Some(vec![0x9a, 0xbc]));
+    }
+
+    #[test]
+    fn test_huge_program_header_table() {
+        let mut exe = elf(&[(PT_NOTE, 0, note(b"GNU", NT_GNU_BUILD_ID, &[0x12, 0x34]))]);
+        assert_eq!(read_program_headers(&mut Cursor::new(&exe), 0).unwrap().unwrap().1.len(), 1);
+        // 32 MB of headers, which are all there but too many to bother with.
+        exe[0x36..0x38].copy_from_slice(&512u16.to_le_bytes());
+        exe[0x38..0x3a].copy_from_slice(&u16::MAX.to_le_bytes());
+        exe.resize(64 + 512 * u16::MAX as usize, 0);
+        assert!(read_program_headers(&mut Cursor::new(&exe), 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_arch() {
+        assert_eq!(arch(&elf32(&[])).as_deref(), Some("i386"));
+        let mut core = elf(&[]);
+        assert_eq!(arch(&core), Some("elf64-machine-0".to_owned()));
+        core[18] = 62;
+        assert_eq!(arch(&core).as_deref(), Some("x86_64"));
+        core[4] = 1;
+        assert_eq!(arch(&core).as_deref(), Some("x32"));
+        // Big-endian.
+        core[5] = 2;
+        assert_eq!(arch(&core), None);
+        assert_eq!(arch(b"\x7fELF"), None);
+    }
+
+    #[test]
+    fn test_siginfo() {
+        let mut desc = Vec::new();
+        desc.extend(11i32.to_le_bytes());
+        desc.extend(0i32.to_le_bytes());
+        desc.extend(1i32.to_le_bytes());
+        desc.extend(0u32.to_le_bytes());
+        desc.extend(0x7fff_dead_beefu64.to_le_bytes());
+        desc.extend([0; 104]);
+        let mut notes = note(b"CORE", 1, &[0; 16]);
+        notes.extend(note(b"CORE", NT_SIGINFO, &desc));
+        let core = elf(&[(PT_NOTE, 0, notes), (PT_LOAD, 0x400000, vec![0; 4096])]);
+        let expected = Siginfo { signo: 11, code: 1, fault_address: Some("0x7fffdeadbeef".to_owned()) };
+        assert_eq!(siginfo(&core), Some(expected.clone()));
+        // Only the notes are needed.
+        assert_eq!(siginfo(&core[..core.len() - 4096]), Some(expected));
+        assert_eq!(siginfo(&core[..200]), None);
+        assert_eq!(siginfo(b"hello"), None);
+
+        // SIGABRT has no fault address.
+        desc[0..4].copy_from_slice(&6i32.to_le_bytes());
+        let core = elf(&[(PT_NOTE, 0, note(b"CORE", NT_SIGINFO, &desc))]);
+        assert_eq!(siginfo(&core), Some(Siginfo { signo: 6, code: 1, fault_address: None }));
+
+        // 32-bit si_addr comes straight after si_code.
+        let mut desc = Vec::new();
+        desc.extend(7i32.to_le_bytes());
+        desc.extend(0i32.to_le_bytes());
+        desc.extend(2i32.to_le_bytes());
+        desc.extend(0xdead_beefu32.to_le_bytes());
+        desc.extend([0; 112]);
+        let core = elf32(&[(PT_NOTE, 0, note(b"CORE", NT_SIGINFO, &desc))]);
+        let expected = Siginfo { signo: 7, code: 2, fault_address: Some("0xdeadbeef".to_owned()) };
+        assert_eq!(siginfo(&core), Some(expected));
+    }
+}
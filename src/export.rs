@@ -0,0 +1,341 @@
+//! `sellafield export`: a table of every core under a directory, for
+//! spreadsheets and reporting tools.
+//!
+//! Cores with a sidecar get its fields. Ones without (e.g. written without
+//! `set_save_metadata(true)`) get whatever their names say. Only the
+//! sidecars and file sizes are read, never the cores, so compressed and
+//! encrypted cores are no different. Rows are written as they're found, so
+//! big trees don't need to fit in memory.
+
+use anyhow::Result;
+use argh::FromArgs;
+use fs_err as fs;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    chunk_path,
+    metadata::{parse_core_name, strip_encodings, Metadata},
+    retention::SIDECAR_SUFFIXES,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("invalid format '{}'; expected 'csv' or 'json'", s)),
+        }
+    }
+}
+
+#[derive(FromArgs)]
+/// Export what's known about every core under a directory as CSV or JSON.
+#[argh(subcommand, name = "export")]
+pub struct ExportOpts {
+    /// directory to export. Subdirectories are included.
+    #[argh(option)]
+    dir: PathBuf,
+
+    /// output format: 'csv' (the default) or 'json', an array of objects.
+    #[argh(option, default = "Format::Csv")]
+    format: Format,
+}
+
+/// One core. Anything that isn't known is `None`.
+#[derive(Debug, Default, Serialize)]
+struct Row {
+    path: String,
+    /// Whether the rest came from a sidecar, rather than the file name.
+    sidecar: bool,
+    /// Size of the core (or its chunks) on disk, after any compression.
+    file_bytes: u64,
+    /// From the extensions, e.g. "zstd compressed", outermost first.
+    encodings: Vec<&'static str>,
+    uid: Option<u32>,
+    username: Option<String>,
+    pid: Option<u32>,
+    time: Option<u32>,
+    capture_time_ms: Option<u64>,
+    exe: Option<String>,
+    full_exe: Option<String>,
+    core_limit: Option<u64>,
+    core_bytes: Option<u64>,
+    truncated: Option<bool>,
+    chunks: Option<u32>,
+    signo: Option<i32>,
+    tags: BTreeMap<String, String>,
+}
+
+/// The CSV columns, in order. These are `Row`'s fields.
+const COLUMNS: &[&str] = &[
+    "path",
+    "sidecar",
+    "file_bytes",
+    "encodings",
+    "uid",
+    "username",
+    "pid",
+    "time",
+    "capture_time_ms",
+    "exe",
+    "full_exe",
+    "core_limit",
+    "core_bytes",
+    "truncated",
+    "chunks",
+    "signo",
+    "tags",
+];
+
+pub fn export(opts: &ExportOpts) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    write_export(&opts.dir, opts.format, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_export(dir: &Path, format: Format, out: &mut impl Write) -> Result<()> {
+    let mut first = true;
+    match format {
+        Format::Csv => writeln!(out, "{}", COLUMNS.join(","))?,
+        Format::Json => write!(out, "[")?,
+    }
+    walk(dir, &mut |row| {
+        match format {
+            Format::Csv => writeln!(out, "{}", csv_line(&row)?)?,
+            Format::Json => {
+                write!(out, "{}\n  {}", if first { "" } else { "," }, serde_json::to_string(&row)?)?;
+            }
+        }
+        first = false;
+        Ok(())
+    })?;
+    if format == Format::Json {
+        writeln!(out, "{}]", if first { "" } else { "\n" })?;
+    }
+    Ok(())
+}
+
+/// Find the cores in `dir` and its subdirectories (without following
+/// symlinks), sorted by name within each directory. Subdirectories that
+/// can't be read are skipped with a warning.
+fn walk(dir: &Path, emit: &mut dyn FnMut(Row) -> Result<()>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if let Err(e) = walk(&path, emit) {
+                eprintln!("Warning: skipping {}: {:#}", path.display(), e);
+            }
+            continue;
+        }
+        if !file_type.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".json") {
+            if let Some(row) = sidecar_row(&path)? {
+                emit(row)?;
+            }
+        } else if name.starts_with("core") && !covered(&path, &name) {
+            emit(name_row(&path, &name)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The core for the sidecar at `path`, or `None` if it isn't one of our
+/// sidecars or the core has gone.
+fn sidecar_row(path: &Path) -> Result<Option<Row>> {
+    let metadata: Metadata = match fs::read(path).ok().and_then(|json| serde_json::from_slice(&json).ok()) {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    let core = path.with_extension("");
+    let files = match metadata.chunks {
+        Some(chunks) => (0..chunks).map(|i| chunk_path(&core, i)).collect(),
+        None => vec![core.clone()],
+    };
+    if !files[0].is_file() {
+        return Ok(None);
+    }
+    let file_name = core.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    Ok(Some(Row {
+        path: core.to_string_lossy().into_owned(),
+        sidecar: true,
+        file_bytes: files.iter().map(|file| file.symlink_metadata().map_or(0, |meta| meta.len())).sum(),
+        encodings: strip_encodings(&file_name).1,
+        uid: Some(metadata.uid),
+        username: Some(metadata.username),
+        pid: Some(metadata.pid),
+        time: Some(metadata.time),
+        capture_time_ms: metadata.capture_time_ms,
+        exe: Some(metadata.exe),
+        full_exe: Some(metadata.full_exe),
+        core_limit: Some(metadata.core_limit),
+        core_bytes: Some(metadata.core_bytes),
+        truncated: Some(metadata.truncated),
+        chunks: metadata.chunks,
+        signo: metadata.siginfo.map(|siginfo| siginfo.signo),
+        tags: metadata.tags,
+    }))
+}
+
+/// Whether the file `name` at `path` is part of another row: a core with a
+/// sidecar, one of its chunks, or the `.status` etc. of any core.
+fn covered(path: &Path, name: &str) -> bool {
+    let sidecar_of = |core: &str| path.with_file_name(format!("{}.json", core)).is_file();
+    if sidecar_of(name) {
+        return true;
+    }
+    // Split cores' chunks are <core>.NNN.
+    if let Some((core, chunk)) = name.rsplit_once('.') {
+        if chunk.len() == 3 && chunk.bytes().all(|b| b.is_ascii_digit()) && sidecar_of(core) {
+            return true;
+        }
+    }
+    SIDECAR_SUFFIXES.iter().any(|suffix| {
+        name.strip_suffix(suffix)
+            .is_some_and(|core| sidecar_of(core) || path.with_file_name(core).is_file())
+    })
+}
+
+/// What the name says about a core without a sidecar: `core.<pid>.<exe>`
+/// (the README's suggestion) or `core.<pid>.<time>.<ms>` (`--fallback-dir`).
+fn name_row(path: &Path, name: &str) -> Result<Row> {
+    let meta = fs::symlink_metadata(path)?;
+    let (pid, exe) = parse_core_name(name);
+    let mut row = Row {
+        path: path.to_string_lossy().into_owned(),
+        file_bytes: meta.len(),
+        encodings: strip_encodings(name).1,
+        pid,
+        exe,
+        ..Default::default()
+    };
+    let fallback = row.exe.as_deref().and_then(|exe| exe.split_once('.')).and_then(|(time, ms)| {
+        Some((time.parse().ok()?, ms.parse().ok()?))
+    });
+    if let Some((time, ms)) = fallback {
+        row.time = Some(time);
+        row.capture_time_ms = Some(ms);
+        row.exe = None;
+    }
+    Ok(row)
+}
+
+fn csv_line(row: &Row) -> Result<String> {
+    let value = serde_json::to_value(row)?;
+    let fields: Vec<String> = COLUMNS
+        .iter()
+        .map(|column| match &value[column] {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => csv_escape(s),
+            serde_json::Value::Array(items) => {
+                csv_escape(&items.iter().filter_map(|item| item.as_str()).collect::<Vec<_>>().join(";"))
+            }
+            serde_json::Value::Object(tags) => csv_escape(
+                &tags
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value.as_str().unwrap_or_default()))
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ),
+            other => other.to_string(),
+        })
+        .collect();
+    Ok(fields.join(","))
+}
+
+/// Quote a field if it needs it, as in RFC 4180.
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_escape, write_export, Format};
+    use std::path::Path;
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("core.1.foo.zst", "0123");
+        write(
+            "core.1.foo.zst.json",
+            r#"{"uid": 1000, "username": "bob", "pid": 1, "time": 1700000000, "exe": "foo", "full_exe": "/usr/bin/foo",
+                "core_limit": 100, "core_bytes": 10, "tags": {"service": "api, v2"}}"#,
+        );
+        write("core.1.foo.zst.head", "01");
+        write("sub/core.2.bar", "012345");
+        write("sub/core.2.bar.status", "");
+        write("sub/core.3.1700000001.123", "0");
+        write("sub/notes.txt", "");
+        write("sub/settings.json", r#"{"not": "a sidecar"}"#);
+
+        let export = |format| {
+            let mut out = Vec::new();
+            write_export(dir.path(), format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let root = dir.path().display();
+        assert_eq!(
+            export(Format::Csv),
+            format!(
+                "path,sidecar,file_bytes,encodings,uid,username,pid,time,capture_time_ms,exe,full_exe,core_limit,\
+                 core_bytes,truncated,chunks,signo,tags\n\
+                 {root}/core.1.foo.zst,true,4,zstd compressed,1000,bob,1,1700000000,,foo,/usr/bin/foo,100,10,false,,,\
+                 \"service=api, v2\"\n\
+                 {root}/sub/core.2.bar,false,6,,,,2,,,bar,,,,,,,\n\
+                 {root}/sub/core.3.1700000001.123,false,1,,,,3,1700000001,123,,,,,,,,\n",
+                root = root
+            )
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&export(Format::Json)).unwrap();
+        let rows = json.as_array().unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["tags"]["service"], "api, v2");
+        assert_eq!(rows[1]["uid"], serde_json::Value::Null);
+
+        let empty = tempfile::tempdir().unwrap();
+        let mut out = Vec::new();
+        write_export(empty.path(), Format::Json, &mut out).unwrap();
+        assert_eq!(out, b"[]\n");
+        assert!(write_export(Path::new("/nonexistent"), Format::Csv, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_csv_escape() {
+        assert_eq!(csv_escape("foo"), "foo");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
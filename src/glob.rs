@@ -0,0 +1,47 @@
+//! Shell-style wildcard matching: `*` matches any run of characters and `?`
+//! matches any one character. Nothing else is special.
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // The usual backtracking matcher; only the most recent `*` ever needs
+    // revisiting so it's linear-ish.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*TOKEN*", "GITHUB_TOKEN"));
+        assert!(matches("*TOKEN*", "TOKEN"));
+        assert!(matches("/usr/bin/*", "/usr/bin/foo"));
+        assert!(matches("core.?", "core.1"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("", ""));
+        assert!(!matches("*TOKEN*", "TOKE"));
+        assert!(!matches("core.?", "core.12"));
+        assert!(!matches("a*b", "aXbY"));
+        assert!(!matches("", "a"));
+    }
+}
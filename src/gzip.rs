@@ -0,0 +1,66 @@
+//! Just enough gzip to read a compressed config script.
+
+use anyhow::{bail, Result};
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// Decompress a single-member gzip file, failing if it comes to more than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    if data.len() < 18 || data[0..2] != [0x1f, 0x8b] {
+        bail!("not a gzip file");
+    }
+    if data[2] != 8 {
+        bail!("unsupported gzip compression method {}", data[2]);
+    }
+    let flags = data[3];
+    // Magic, method, flags, mtime, extra flags and OS.
+    let mut pos = 10;
+    let truncated = || anyhow::anyhow!("gzip header is truncated");
+    if flags & FEXTRA != 0 {
+        let len = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let nul = data.get(pos..).and_then(|rest| rest.iter().position(|&b| b == 0)).ok_or_else(truncated)?;
+            pos += nul + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    // The trailer is a CRC and the size, which miniz doesn't check for us.
+    let deflated = data.get(pos..data.len() - 8).ok_or_else(truncated)?;
+    let output = miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, limit)
+        .map_err(|e| anyhow::anyhow!("error decompressing: {:?} (or bigger than {} bytes)", e, limit))?;
+    let size = &data[data.len() - 4..];
+    if u32::from_le_bytes([size[0], size[1], size[2], size[3]]) != output.len() as u32 {
+        bail!("decompressed size doesn't match the gzip trailer; the file is probably corrupt");
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::decompress;
+
+    /// `set_output_path("/tmp/core");` from `gzip`, with a file name.
+    pub const CONFIG_GZ: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x63, 0x6f, 0x6e, 0x66, 0x69, 0x67, 0x2e, 0x72,
+        0x68, 0x61, 0x69, 0x00, 0x2b, 0x4e, 0x2d, 0x89, 0xcf, 0x2f, 0x2d, 0x29, 0x28, 0x2d, 0x89, 0x2f, 0x48, 0x2c,
+        0xc9, 0xd0, 0x50, 0xd2, 0x2f, 0xc9, 0x2d, 0xd0, 0x4f, 0xce, 0x2f, 0x4a, 0x55, 0xd2, 0xb4, 0x06, 0x00, 0x4b,
+        0x40, 0xa7, 0x20, 0x1d, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(decompress(CONFIG_GZ, 1000).unwrap(), br#"set_output_path("/tmp/core");"#);
+        assert!(decompress(CONFIG_GZ, 10).is_err());
+        assert!(decompress(&CONFIG_GZ[..30], 1000).is_err());
+        assert!(decompress(b"set_output_path(\"/tmp/core\");", 1000).is_err());
+    }
+}
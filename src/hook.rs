@@ -0,0 +1,71 @@
+//! `set_post_hook()`: run a command after a core has been written, e.g. to
+//! tell an indexer about it.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run `command` with `sh -c`, with the core path as `$1` and `env` set. It's
+/// killed (with anything it started) if it takes longer than `timeout`.
+pub fn run(command: &str, core: &Path, env: &[(&str, String)], timeout: Duration) -> Result<()> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sellafield")
+        .arg(core)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // Its own process group so we can kill the lot.
+        .process_group(0)
+        .spawn()
+        .context("error starting post hook")?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("post hook failed ({})", status);
+            }
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+            bail!("post hook didn't finish within {} seconds; killed it", timeout.as_secs());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = dir.path().join("core");
+        let marker = dir.path().join("marker");
+        let command = format!(r#"echo "$1 $SELLAFIELD_PID" > {}"#, marker.display());
+        run(&command, &core, &[("SELLAFIELD_PID", "42".to_owned())], Duration::from_secs(10)).unwrap();
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), format!("{} 42\n", core.display()));
+
+        let error = run("exit 3", &core, &[], Duration::from_secs(10)).unwrap_err().to_string();
+        assert!(error.contains("exit status: 3"), "{}", error);
+
+        let start = Instant::now();
+        let error = run("sleep 10", &core, &[], Duration::from_millis(200)).unwrap_err().to_string();
+        assert!(error.contains("didn't finish"), "{}", error);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
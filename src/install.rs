@@ -0,0 +1,406 @@
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{existing_ancestor, is_writable_dir, kernel, run_policy_script, DEFAULT_CONFIG, PACKED_TEMPLATE};
+
+/// The kernel stores `core_pattern` in a 128 byte buffer (`CORENAME_MAX_SIZE`)
+/// including the terminating NUL, and silently truncates anything longer.
+pub const CORE_PATTERN_MAX_LEN: usize = 127;
+
+const CORE_PATTERN_PATH: &str = "/proc/sys/kernel/core_pattern";
+
+/// Where `install` saves the core pattern it replaced, for `uninstall`.
+const PREVIOUS_PATTERN_PATH: &str = "/var/lib/sellafield/previous_core_pattern";
+
+#[derive(FromArgs)]
+/// Set `kernel.core_pattern` to pipe core dumps to sellafield.
+#[argh(subcommand, name = "install")]
+pub struct InstallOpts {
+    /// config file to pass to sellafield. It is left out of the core pattern
+    /// if it is the default (/etc/sellafield.rhai), which saves a lot of space.
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// path to the sellafield executable. Defaults to this executable.
+    #[argh(option)]
+    binary: Option<PathBuf>,
+
+    /// pass the crash details as one --packed argument, which makes the
+    /// core pattern shorter.
+    #[argh(switch)]
+    packed: bool,
+
+    /// print the core pattern instead of installing it.
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// replace the core pattern even if it pipes to another crash handler,
+    /// e.g. systemd-coredump or apport.
+    #[argh(switch)]
+    force: bool,
+}
+
+pub fn install(opts: &InstallOpts) -> Result<()> {
+    let binary = match &opts.binary {
+        Some(binary) => binary.clone(),
+        None => std::env::current_exe().context("error finding sellafield executable")?,
+    };
+
+    let kernel = kernel::version();
+    if opts.packed && kernel.is_some_and(|kernel| !kernel::supports(kernel, 'd')) {
+        bail!("--packed needs %d, which needs Linux 3.7 or later");
+    }
+    let line = core_pattern_line(&binary, opts.config.as_deref(), opts.packed, kernel);
+
+    if opts.dry_run {
+        println!("{}", line);
+    }
+
+    if line.len() > CORE_PATTERN_MAX_LEN {
+        bail!(
+            "core pattern is {} characters but the kernel truncates it to {}. \
+             Move sellafield to a shorter path or use the default config location ({}).\n{}",
+            line.len(),
+            CORE_PATTERN_MAX_LEN,
+            DEFAULT_CONFIG,
+            line
+        );
+    }
+
+    let current = fs::read_to_string(CORE_PATTERN_PATH)?;
+    let current = current.trim_end_matches('\n');
+    if let Err(e) = check_replaceable(current, &binary, opts.force) {
+        if !opts.dry_run {
+            return Err(e);
+        }
+        println!("Warning: {:#}", e);
+    }
+
+    if !opts.dry_run {
+        // Reinstalling shouldn't lose the original.
+        if !is_sellafield(current, &binary) {
+            save_previous(Path::new(PREVIOUS_PATTERN_PATH), current)?;
+        }
+        fs::write(CORE_PATTERN_PATH, &line)?;
+        println!("Installed core pattern: {}", line);
+        let config = opts.config.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+        print_checks(&checks(&binary, &config));
+    }
+    Ok(())
+}
+
+/// The program a `|` core pattern pipes to.
+fn pipe_handler(pattern: &str) -> Option<&str> {
+    pattern.strip_prefix('|')?.split_whitespace().next()
+}
+
+/// Whether `pattern` pipes to sellafield (this binary or another copy).
+fn is_sellafield(pattern: &str, binary: &Path) -> bool {
+    pipe_handler(pattern).is_some_and(|handler| {
+        Path::new(handler) == binary
+            || Path::new(handler).file_name().is_some_and(|name| name.to_string_lossy().starts_with("sellafield"))
+    })
+}
+
+/// Don't quietly disable another crash handler. Plain file patterns like
+/// `core` are fine to replace.
+fn check_replaceable(current: &str, binary: &Path, force: bool) -> Result<()> {
+    match pipe_handler(current) {
+        Some(handler) if !force && !is_sellafield(current, binary) => bail!(
+            "core pattern already pipes to {}, another crash handler; use --force to replace it anyway. \
+             It's currently: {}",
+            handler,
+            current
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn save_previous(path: &Path, pattern: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, pattern).with_context(|| format!("error saving the previous core pattern to {}", path.display()))
+}
+
+#[derive(FromArgs)]
+/// Put back the core pattern that `sellafield install` replaced.
+#[argh(subcommand, name = "uninstall")]
+pub struct UninstallOpts {}
+
+pub fn uninstall(_opts: &UninstallOpts) -> Result<()> {
+    let current = fs::read_to_string(CORE_PATTERN_PATH)?;
+    let current = current.trim_end_matches('\n');
+    let binary = std::env::current_exe().context("error finding sellafield executable")?;
+    if !is_sellafield(current, &binary) {
+        bail!("core pattern doesn't pipe to sellafield so there's nothing to uninstall. It's: {}", current);
+    }
+    let previous = match fs::read_to_string(PREVIOUS_PATTERN_PATH) {
+        Ok(previous) => previous,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!(
+            "{} doesn't exist so the previous core pattern isn't known; set kernel.core_pattern yourself",
+            PREVIOUS_PATTERN_PATH
+        ),
+        Err(e) => return Err(e.into()),
+    };
+    fs::write(CORE_PATTERN_PATH, &previous)?;
+    fs::remove_file(PREVIOUS_PATTERN_PATH)?;
+    println!("Restored core pattern: {}", previous);
+    Ok(())
+}
+
+#[derive(FromArgs)]
+/// Check that sellafield is set up so it will actually be able to write
+/// cores: the binary, the config and where it writes to.
+#[argh(subcommand, name = "doctor")]
+pub struct DoctorOpts {
+    /// config file sellafield is run with. Defaults to /etc/sellafield.rhai.
+    #[argh(option, default = "PathBuf::from(DEFAULT_CONFIG)")]
+    config: PathBuf,
+
+    /// path to the sellafield executable. Defaults to this executable.
+    #[argh(option)]
+    binary: Option<PathBuf>,
+}
+
+pub fn doctor(opts: &DoctorOpts) -> Result<()> {
+    let binary = match &opts.binary {
+        Some(binary) => binary.clone(),
+        None => std::env::current_exe().context("error finding sellafield executable")?,
+    };
+    let checks = checks(&binary, &opts.config);
+    print_checks(&checks);
+    let failed = checks.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+/// A check's description and whether it passed (with the reason if not).
+type Check = (String, Result<(), String>);
+
+fn print_checks(checks: &[Check]) {
+    for (description, result) in checks {
+        match result {
+            Ok(()) => println!("[ok]   {}", description),
+            Err(reason) => println!("[FAIL] {}: {}", description, reason),
+        }
+    }
+}
+
+/// Sellafield runs as root and then switches to the crashing user, so anyone
+/// who can change the binary or config can run code as root or redirect
+/// everyone's cores.
+fn check_root_owned(path: &Path) -> Result<(), String> {
+    let meta = fs::metadata(path).map_err(|e| e.to_string())?;
+    if meta.uid() != 0 {
+        return Err(format!("owned by user ID {} rather than root", meta.uid()));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return Err(format!("writable by group or others (mode {:04o})", meta.mode() & 0o7777));
+    }
+    Ok(())
+}
+
+fn checks(binary: &Path, config: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    // The kernel runs core_pattern helpers as root, which is what lets us
+    // switch to any user; the binary doesn't need to be setuid.
+    let executable = fs::metadata(binary)
+        .map_err(|e| e.to_string())
+        .and_then(|meta| match meta.mode() & 0o100 {
+            0 => Err("not executable".to_owned()),
+            _ => Ok(()),
+        });
+    checks.push((format!("{} is executable", binary.display()), executable));
+    checks.push((
+        format!("{} is owned by root and not writable by anyone else", binary.display()),
+        check_root_owned(binary),
+    ));
+
+    match fs::read_to_string(CORE_PATTERN_PATH) {
+        Ok(pattern) => {
+            let piped = format!("|{} ", binary.display());
+            let result = if pattern.starts_with(&piped) {
+                Ok(())
+            } else {
+                Err(format!("it's {}", pattern.trim_end()))
+            };
+            checks.push((format!("core_pattern pipes to {}", binary.display()), result));
+        }
+        Err(e) => checks.push(("core_pattern is readable".to_owned(), Err(e.to_string()))),
+    }
+
+    let readable = fs::read(config).map(|_| ()).map_err(|e| e.to_string());
+    checks.push((format!("{} is readable", config.display()), readable));
+    checks.push((
+        format!("{} is owned by root and not writable by anyone else", config.display()),
+        check_root_owned(config),
+    ));
+
+    // Try it with ourselves as the crashing user.
+    let resolved = match run_policy_script(config) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            checks.push((format!("{} runs", config.display()), Err(format!("{:#}", e))));
+            return checks;
+        }
+    };
+    checks.push((format!("{} runs", config.display()), Ok(())));
+    let conflicts = resolved.conflicts();
+    if conflicts.is_empty() {
+        checks.push(("config settings are consistent".to_owned(), Ok(())));
+    }
+    for conflict in conflicts {
+        checks.push(("config settings are consistent".to_owned(), Err(conflict)));
+    }
+    for output in resolved.outputs() {
+        let dir = existing_ancestor(&output.path);
+        let writable = if is_writable_dir(&dir) {
+            Ok(())
+        } else {
+            Err(format!("{} isn't writable", dir.display()))
+        };
+        checks.push((format!("{} can be written for a test crash", output.path.display()), writable));
+    }
+    checks
+}
+
+#[derive(FromArgs)]
+/// Show the current `kernel.core_pattern` and check it will work with this
+/// kernel.
+#[argh(subcommand, name = "status")]
+pub struct StatusOpts {}
+
+pub fn status(_opts: &StatusOpts) -> Result<()> {
+    let pattern = fs::read_to_string(CORE_PATTERN_PATH)?;
+    let pattern = pattern.trim_end_matches('\n');
+    println!("Core pattern: {}", pattern);
+
+    let kernel = kernel::version();
+    match kernel {
+        Some((major, minor)) => println!("Kernel: {}.{}", major, minor),
+        None => println!("Kernel: unknown"),
+    }
+
+    if !pattern.starts_with('|') || !pattern.contains("sellafield") {
+        println!("Warning: cores aren't being piped to sellafield");
+    }
+    if pattern.len() >= CORE_PATTERN_MAX_LEN {
+        println!("Warning: core pattern is {} characters so it has probably been truncated", pattern.len());
+    }
+    if let Some(kernel) = kernel {
+        let unsupported = kernel::unsupported_specifiers(pattern, kernel);
+        if !unsupported.is_empty() {
+            let unsupported: Vec<String> = unsupported.iter().map(|s| format!("%{}", s)).collect();
+            println!(
+                "Warning: this kernel doesn't support {}; they will be empty. Run `sellafield install` to fix it.",
+                unsupported.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Generate the `core_pattern` line, leaving out anything we can to keep it
+/// short and anything `kernel` doesn't support.
+pub fn core_pattern_line(
+    binary: &Path,
+    config: Option<&Path>,
+    packed: bool,
+    kernel: Option<kernel::Version>,
+) -> String {
+    // If we don't know the kernel version assume it's new enough for %d but
+    // not %f, which both old and new kernels are fine with.
+    let mut line = if packed {
+        format!("|{} -P {} -s %s", binary.display(), PACKED_TEMPLATE)
+    } else {
+        let mut line = format!("|{} -u %u -p %p -t %t -E %E -c %c", binary.display());
+        if kernel.is_none_or(|kernel| kernel::supports(kernel, 'd')) {
+            line.push_str(" -d %d");
+        }
+        line.push_str(" -s %s");
+        line
+    };
+    if kernel.is_some_and(|kernel| kernel::supports(kernel, 'f')) {
+        line.push_str(" -f %f");
+    }
+    if let Some(config) = config {
+        if config != Path::new(DEFAULT_CONFIG) {
+            line.push_str(&format!(" --config {}", config.display()));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_replaceable, check_root_owned, core_pattern_line, is_sellafield, CORE_PATTERN_MAX_LEN};
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    #[test]
+    fn test_check_root_owned() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.rhai");
+        std::fs::write(&config, "").unwrap();
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let root = unsafe { libc::getuid() } == 0;
+        assert_eq!(check_root_owned(&config).is_ok(), root);
+
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(check_root_owned(&config).is_err());
+        assert!(check_root_owned(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_check_replaceable() {
+        let binary = Path::new("/usr/bin/sellafield");
+        let systemd = "|/usr/lib/systemd/systemd-coredump %P %u %g %s %t %c %h";
+        let error = check_replaceable(systemd, binary, false).unwrap_err().to_string();
+        assert!(error.contains("systemd-coredump") && error.contains("--force"), "{}", error);
+        assert!(check_replaceable(systemd, binary, true).is_ok());
+
+        for replaceable in ["core", "/var/crash/core.%p", "|/usr/bin/sellafield -P %u:%p:%t:%c:%d:%E", "|/sf -u %u"] {
+            let binary = Path::new("/sf");
+            assert!(check_replaceable(replaceable, binary, false).is_ok(), "{}", replaceable);
+        }
+
+        assert!(is_sellafield("|/opt/bin/sellafield-1.2 -u %u", binary));
+        assert!(!is_sellafield("core", binary));
+        assert!(!is_sellafield(systemd, binary));
+    }
+
+    #[test]
+    fn test_core_pattern_line() {
+        let line = core_pattern_line(Path::new("/usr/bin/sellafield"), None, false, None);
+        assert_eq!(line, "|/usr/bin/sellafield -u %u -p %p -t %t -E %E -c %c -d %d -s %s");
+        assert!(line.len() <= CORE_PATTERN_MAX_LEN);
+
+        let default_config = Some(Path::new("/etc/sellafield.rhai"));
+        let line = core_pattern_line(Path::new("/usr/bin/sellafield"), default_config, false, None);
+        assert_eq!(line, "|/usr/bin/sellafield -u %u -p %p -t %t -E %E -c %c -d %d -s %s");
+
+        let line = core_pattern_line(Path::new("/sf"), Some(Path::new("/opt/cfg.rhai")), false, None);
+        assert_eq!(line, "|/sf -u %u -p %p -t %t -E %E -c %c -d %d -s %s --config /opt/cfg.rhai");
+
+        let line = core_pattern_line(Path::new("/usr/bin/sellafield"), None, true, None);
+        assert_eq!(line, "|/usr/bin/sellafield -P %u:%p:%t:%c:%d:%E -s %s");
+
+        // %f needs 5.3.
+        let line = core_pattern_line(Path::new("/usr/bin/sellafield"), None, true, Some((5, 2)));
+        assert_eq!(line, "|/usr/bin/sellafield -P %u:%p:%t:%c:%d:%E -s %s");
+        let line = core_pattern_line(Path::new("/sf"), Some(Path::new("/opt/cfg.rhai")), false, Some((6, 1)));
+        assert_eq!(line, "|/sf -u %u -p %p -t %t -E %E -c %c -d %d -s %s -f %f --config /opt/cfg.rhai");
+
+        // %d needs 3.7.
+        let line = core_pattern_line(Path::new("/sf"), None, false, Some((3, 2)));
+        assert_eq!(line, "|/sf -u %u -p %p -t %t -E %E -c %c -s %s");
+    }
+}
@@ -0,0 +1,105 @@
+//! Working out what the running kernel supports.
+
+use std::{ffi::CStr, sync::OnceLock};
+
+/// A kernel version like `(5, 3)`. Tuples compare the right way round.
+pub type Version = (u32, u32);
+
+/// `core_pattern` specifiers and the kernel version that added them. Ones that
+/// aren't listed have been around forever.
+const SPECIFIERS: &[(char, Version)] = &[
+    ('c', (2, 6)),
+    ('E', (3, 0)),
+    ('d', (3, 7)),
+    ('P', (3, 12)),
+    ('i', (3, 18)),
+    ('I', (3, 18)),
+    ('f', (5, 3)),
+];
+
+/// Whether `kernel` expands `%<specifier>`. Older kernels just drop ones they
+/// don't know about.
+pub fn supports(kernel: Version, specifier: char) -> bool {
+    SPECIFIERS
+        .iter()
+        .find(|(s, _)| *s == specifier)
+        .is_none_or(|(_, since)| kernel >= *since)
+}
+
+/// The specifiers in `pattern` that `kernel` doesn't support.
+pub fn unsupported_specifiers(pattern: &str, kernel: Version) -> Vec<char> {
+    let mut unsupported = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        match chars.next() {
+            Some(specifier) if !supports(kernel, specifier) && !unsupported.contains(&specifier) => {
+                unsupported.push(specifier)
+            }
+            _ => {}
+        }
+    }
+    unsupported
+}
+
+/// The running kernel's version from `uname()`, if it can be parsed.
+#[cfg(unix)]
+pub fn version() -> Option<Version> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    parse_version(&release.to_string_lossy())
+}
+
+/// The boot ID, as 32 hex digits without dashes like systemd and the
+/// journal use. It's only read once.
+pub fn boot_id() -> Option<String> {
+    static BOOT_ID: OnceLock<Option<String>> = OnceLock::new();
+    BOOT_ID
+        .get_or_init(|| {
+            let id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+            Some(id.trim().replace('-', ""))
+        })
+        .clone()
+}
+
+/// Parse the major and minor version from a release like `5.15.0-91-generic`.
+pub fn parse_version(release: &str) -> Option<Version> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{boot_id, parse_version, supports, unsupported_specifiers, version};
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(parse_version("6.1"), Some((6, 1)));
+        assert_eq!(parse_version("4.19.0+"), Some((4, 19)));
+        assert_eq!(parse_version("6"), None);
+        assert_eq!(parse_version(""), None);
+        assert!(version().is_some());
+        assert_eq!(boot_id().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_specifiers() {
+        assert!(supports((3, 7), 'd'));
+        assert!(!supports((3, 6), 'd'));
+        assert!(supports((2, 6), 'u'));
+        assert!(!supports((5, 2), 'f'));
+
+        let pattern = "|/sf -u %u -p %P -t %t -E %E -c %c -d %d -f %f %%f";
+        assert_eq!(unsupported_specifiers(pattern, (6, 1)), vec![]);
+        assert_eq!(unsupported_specifiers(pattern, (5, 2)), vec!['f']);
+        assert_eq!(unsupported_specifiers(pattern, (3, 10)), vec!['P', 'f']);
+    }
+}
@@ -0,0 +1,62 @@
+//! `set_layout()`: name cores like another crash handler so its tools work.
+
+use std::str::FromStr;
+
+/// Where systemd-coredump keeps cores.
+pub const SYSTEMD_COREDUMP_DIR: &str = "/var/lib/systemd/coredump";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Wherever `set_output_path()` says.
+    #[default]
+    Default,
+    /// `<SYSTEMD_COREDUMP_DIR>/core.COMM.UID.BOOTID.PID.TIME`.
+    Systemd,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Layout::Default),
+            "systemd" => Ok(Layout::Systemd),
+            _ => Err(format!("invalid layout '{}'; expected 'default' or 'systemd'", s)),
+        }
+    }
+}
+
+/// systemd-coredump's file name for a core (without compression). The time
+/// is in microseconds, and `comm` is escaped so it can't add dots or
+/// slashes.
+pub fn systemd_core_name(comm: &str, uid: u32, boot_id: &str, pid: u32, time: u32) -> String {
+    format!("core.{}.{}.{}.{}.{}", escape(comm), uid, boot_id, pid, time as u64 * 1_000_000)
+}
+
+/// Like systemd's `xescape(comm, "./ ")`.
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for &b in s.as_bytes() {
+        if !(b' '..127).contains(&b) || b == b'\\' || b"./ ".contains(&b) {
+            out.push_str(&format!("\\x{:02x}", b));
+        } else {
+            out.push(b as char);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::systemd_core_name;
+
+    #[test]
+    fn test_systemd_core_name() {
+        assert_eq!(
+            systemd_core_name("foo", 1000, "0123456789abcdef0123456789abcdef", 42, 1700000000),
+            "core.foo.1000.0123456789abcdef0123456789abcdef.42.1700000000000000"
+        );
+        assert_eq!(systemd_core_name("a.b/c d", 0, "x", 1, 0), r"core.a\x2eb\x2fc\x20d.0.x.1.0");
+    }
+}
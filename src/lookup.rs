@@ -0,0 +1,95 @@
+//! `lookup(file, key)`: read routing tables and the like from a simple
+//! `key=value` file rather than embedding them in the config script.
+
+use anyhow::{bail, Result};
+use fs_err as fs;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Larger files are probably a mistake, and we're in a crash handler.
+pub const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Parse `key=value` lines. Blank lines and lines starting with `#` are
+/// ignored, as are lines without an `=`. Whitespace around keys and values is
+/// trimmed and later lines override earlier ones.
+pub fn parse(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, String>> {
+    let mut text = String::new();
+    fs::File::open(path)?.take(MAX_FILE_BYTES + 1).read_to_string(&mut text)?;
+    if text.len() as u64 > MAX_FILE_BYTES {
+        bail!("{} is larger than {} bytes", path.display(), MAX_FILE_BYTES);
+    }
+    Ok(parse(&text))
+}
+
+/// Parsed files, so looking up lots of keys doesn't reread the file.
+#[derive(Default)]
+pub struct Lookup {
+    files: HashMap<PathBuf, BTreeMap<String, String>>,
+}
+
+impl Lookup {
+    /// The value for `key` in `path`, or empty if there isn't one. A file that
+    /// can't be read is treated as empty after calling `warn` once.
+    pub fn get(&mut self, path: &Path, key: &str, warn: impl FnOnce(&anyhow::Error)) -> String {
+        self.files
+            .entry(path.to_owned())
+            .or_insert_with(|| {
+                read(path).unwrap_or_else(|e| {
+                    warn(&e);
+                    BTreeMap::new()
+                })
+            })
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Lookup, MAX_FILE_BYTES};
+
+    #[test]
+    fn test_parse() {
+        let map = parse("# teams\nfoo = /crash/a\n\nbar=/crash/b=c\nnonsense\nfoo=/crash/c\n");
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["foo"], "/crash/c");
+        assert_eq!(map["bar"], "/crash/b=c");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("teams");
+        std::fs::write(&path, "foo=/crash/a\n").unwrap();
+
+        let mut lookup = Lookup::default();
+        let mut warnings = 0;
+        assert_eq!(lookup.get(&path, "foo", |_| warnings += 1), "/crash/a");
+        assert_eq!(lookup.get(&path, "bar", |_| warnings += 1), "");
+        // It's cached.
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lookup.get(&path, "foo", |_| warnings += 1), "/crash/a");
+        assert_eq!(warnings, 0);
+
+        let big = dir.path().join("big");
+        std::fs::write(&big, "a=b\n".repeat(MAX_FILE_BYTES as usize)).unwrap();
+        for missing in [dir.path().join("missing"), big] {
+            assert_eq!(lookup.get(&missing, "a", |_| warnings += 1), "");
+            assert_eq!(lookup.get(&missing, "a", |_| warnings += 1), "");
+        }
+        assert_eq!(warnings, 2);
+    }
+}
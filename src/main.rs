@@ -2,16 +2,55 @@ use anyhow::{anyhow, bail, Context, Result};
 use argh::FromArgs;
 use errno::{errno, set_errno, Errno};
 use fs_err as fs;
-use rhai::{Engine, OptimizationLevel, Scope};
+use fs_err::os::unix::fs::OpenOptionsExt;
+use rhai::{Engine, EvalAltResult, OptimizationLevel, Scope};
+use serde::Serialize;
 use std::{
-    ffi::{CStr, OsStr},
-    io::{self, BufWriter, Read, Write},
-    os::unix::ffi::OsStrExt,
+    ffi::{CStr, CString, OsStr},
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
     path::{Path, PathBuf},
     rc::Rc,
+    str::FromStr,
     cell::RefCell,
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
+mod api;
+mod bench;
+mod cat;
+mod counter;
+mod debounce;
+mod drain;
+mod elf;
+mod export;
+mod glob;
+mod gzip;
+mod hook;
+mod install;
+mod kernel;
+mod layout;
+mod lookup;
+mod metadata;
+mod mounts;
+mod permissions;
+mod process;
+mod reassemble;
+mod retention;
+mod sampling;
+mod simulate;
+mod slots;
+mod state;
+mod storm;
+
+use metadata::Metadata;
+use process::ProcessInfo;
+
 // # `core_pattern` options
 //
 // %%  A single % character.
@@ -24,7 +63,8 @@ use std::{
 //     and truncated to a maximum of 15 characters), but may
 //     have been modified to be something different; see the
 //     discussion of /proc/[pid]/comm and
-//     /proc/[pid]/task/[tid]/comm in proc(5).
+//     /proc/[pid]/task/[tid]/comm in proc(5). Sellafield takes
+//     this as -e; it's only a hint, see comm().
 // %E  Pathname of executable, with slashes ('/') replaced by
 //     exclamation marks ('!') (since Linux 3.0).
 // %g  Numeric real GID of dumped process.
@@ -42,10 +82,10 @@ use std::{
 // %t  Time of dump, expressed as seconds since the Epoch,
 //     1970-01-01 00:00:00 +0000 (UTC).
 // %u  Numeric real UID of dumped process.
+// %f  Executable filename without path prefix, not truncated like %e
+//     (since Linux 5.3).
 
-// Note there's also %f for the filename, but it's only from 2019 so we won't use it.
-
-#[derive(FromArgs)]
+#[derive(FromArgs, Default, Debug)]
 /// Configurably write core dumps. This can be used to avoid filling up HOME and
 /// to make core dumps world-readable.
 /// See https://man7.org/linux/man-pages/man5/core.5.html
@@ -58,27 +98,461 @@ struct Opts {
     #[argh(option, short = 'p')]
     pid: u32,
 
-    /// unix time in millis when the process crashed. Use '%t' for this.
+    /// unix time in seconds when the process crashed. Use '%t' for this.
     #[argh(option, short = 't')]
     time: u32,
 
-    /// path to the executable. !'s are replaced with /. Use %E for this.
+    /// path to the executable. !'s are replaced with / (see
+    /// --exe-encoding). Use %E for this.
     #[argh(option, short = 'E')]
     exe: String,
 
+    /// how -E is encoded: 'bang' (the default) if /'s are replaced with !'s,
+    /// as %E does, or 'raw' if it's a real path, e.g. from a wrapper.
+    #[argh(option, default = "ExeEncoding::Bang")]
+    exe_encoding: ExeEncoding,
+
     /// core file size limit. Use %c for this.
     #[argh(option, short = 'c')]
     core_limit: u64,
 
-    /// location of the config file that determines output location and permissions
-    #[argh(option)]
+    /// dump mode of the crashed process (1 normally, 2 if it's only being
+    /// dumped because of suid_dumpable=2). Use %d for this.
+    #[argh(option, short = 'd')]
+    dump_mode: Option<u32>,
+
+    /// number of the signal that caused the dump. Use %s for this.
+    #[argh(option, short = 's')]
+    signal: Option<u32>,
+
+    /// file name of the executable (not truncated like %e). Use %f for this;
+    /// it needs Linux 5.3 or later.
+    #[argh(option, short = 'f')]
+    kernel_filename: Option<String>,
+
+    /// comm of the crashing thread, which the process can change and is
+    /// truncated to 15 characters. Use %e for this.
+    #[argh(option, short = 'e')]
+    comm: Option<String>,
+
+    /// all of -u, -p, -t, -c, -d and -E packed into one argument, to keep the
+    /// core pattern short. Use '%u:%p:%t:%c:%d:%E' for this.
+    #[argh(option, short = 'P')]
+    // Only here for --help and validation; unpack_args() does the work.
+    #[allow(dead_code)]
+    packed: Option<String>,
+
+    /// location of the config file that determines output location and
+    /// permissions. Defaults to /etc/sellafield.rhai.
+    #[argh(option, default = "PathBuf::from(DEFAULT_CONFIG)")]
     config: PathBuf,
+
+    /// write the config resolved by the script to this path as JSON. Use
+    /// /dev/fd/N for a file descriptor. For testing and debugging.
+    #[argh(option)]
+    emit_config_json: Option<PathBuf>,
+
+    /// read the core from this file instead of stdin, for testing configs
+    /// without crashing anything.
+    #[argh(option)]
+    input: Option<PathBuf>,
+
+    /// directory to write the core to if it can't be written where the
+    /// config script says, e.g. because the user's home directory doesn't
+    /// exist.
+    #[argh(option)]
+    fallback_dir: Option<PathBuf>,
+
+    /// write cores (and run the post hook) as this user ID rather than the
+    /// crashing user, e.g. a dedicated coredump account. The script still
+    /// sees the crashing user.
+    #[argh(option)]
+    output_uid: Option<u32>,
+
+    /// write cores with this group ID rather than the primary group of the
+    /// user they're written as.
+    #[argh(option)]
+    output_gid: Option<u32>,
+
+    /// only write cores caused by these signals, e.g. '6,11' for SIGABRT and
+    /// SIGSEGV. The script can override this with set_capture_signals().
+    /// Defaults to all signals.
+    #[argh(option, from_str_fn(parse_signals))]
+    signals: Option<Vec<u32>>,
+
+    /// directory that relative paths from the config script are relative to.
+    /// Defaults to the user's home directory.
+    #[argh(option)]
+    base_dir: Option<PathBuf>,
+
+    /// treat a config script that doesn't call set_output_path() or skip()
+    /// as an error, rather than skipping the core.
+    #[argh(switch)]
+    require_output: bool,
+
+    /// exit straight away if no core is written instead of reading the rest
+    /// of it from stdin first.
+    #[argh(switch)]
+    no_drain_on_skip: bool,
+
+    /// log each step (arguments, user, every config call the script makes,
+    /// the final config and what was written) to help work out where a core
+    /// went.
+    #[argh(switch)]
+    verbose: bool,
+
+    /// log how much of the core has been written to syslog every 10
+    /// seconds or 1GB, for keeping an eye on very large cores.
+    #[argh(switch)]
+    progress: bool,
+
+    /// log a heartbeat to syslog every this many seconds while the script is
+    /// running or the core is being written, saying which, so a wedged
+    /// sellafield can be diagnosed. Off by default.
+    #[argh(option)]
+    heartbeat_secs: Option<u64>,
+
+    /// give up and delete any partially written core if handling the crash
+    /// takes longer than this many seconds, so a hung disk or script can't
+    /// block the kernel forever. 0 for no limit. Defaults to 3600.
+    #[argh(option, default = "3600")]
+    deadline: u64,
+
+    /// maximum number of sellafields handling crashes at once across the
+    /// whole system, so a crash storm doesn't make things worse. 0 for no
+    /// limit. Defaults to 8.
+    #[argh(option, default = "8")]
+    max_concurrent: usize,
+
+    /// what to do if --max-concurrent are already running: 'skip' the core
+    /// (the default) or 'wait' up to 30 seconds for a slot.
+    #[argh(option, default = "slots::Overload::Skip")]
+    on_overload: slots::Overload,
+
+    /// stop writing cores after this many, until `sellafield reset-counter`
+    /// is run (or a reboot). A safety brake for runaway crash storms. Off by
+    /// default.
+    #[argh(option)]
+    max_total_dumps: Option<u64>,
+
+    /// directory for the --max-concurrent lock files. Defaults to
+    /// /run/sellafield.
+    #[argh(option, default = "PathBuf::from(DEFAULT_LOCK_DIR)")]
+    lock_dir: PathBuf,
+}
+
+/// Config file used if `--config` isn't given. Leaving it out keeps the
+/// `core_pattern` line short.
+const DEFAULT_CONFIG: &str = "/etc/sellafield.rhai";
+
+const DEFAULT_LOCK_DIR: &str = "/run/sellafield";
+
+#[derive(FromArgs)]
+/// Sellafield management commands. When run from `core_pattern` sellafield
+/// takes the crash options instead of a subcommand; see `sellafield --help`.
+struct Tool {
+    #[argh(subcommand)]
+    command: ToolCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ToolCommand {
+    Install(install::InstallOpts),
+    Uninstall(install::UninstallOpts),
+    Prune(retention::PruneOpts),
+    Gc(retention::GcOpts),
+    Show(metadata::ShowOpts),
+    Export(export::ExportOpts),
+    BuildId(elf::BuildIdOpts),
+    Status(install::StatusOpts),
+    Doctor(install::DoctorOpts),
+    Bench(bench::BenchOpts),
+    Reassemble(reassemble::ReassembleOpts),
+    Cat(cat::CatOpts),
+    ResetCounter(counter::ResetCounterOpts),
+    Simulate(simulate::SimulateOpts),
+    Api(api::ApiOpts),
+}
+
+/// How the `-E` path is encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ExeEncoding {
+    /// `/` replaced with `!`, like `%E`.
+    #[default]
+    Bang,
+    /// A real path.
+    Raw,
+}
+
+impl FromStr for ExeEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bang" => Ok(ExeEncoding::Bang),
+            "raw" => Ok(ExeEncoding::Raw),
+            _ => Err(format!("invalid exe encoding '{}'; expected 'bang' or 'raw'", s)),
+        }
+    }
+}
+
+/// Turn `-E` back into a path.
+fn decode_exe(exe: &str, encoding: ExeEncoding) -> String {
+    match encoding {
+        // Better hope nobody puts ! in their filenames!
+        ExeEncoding::Bang => exe.replace('!', "/"),
+        ExeEncoding::Raw => exe.to_owned(),
+    }
+}
+
+/// The file name part of the executable's path. Scripts put it straight
+/// into paths, so `.` and `..` (which aren't file names anyway) are no name.
+fn exe_name(full_exe: &str) -> String {
+    match full_exe.rsplit_once('/') {
+        Some((_, "." | "..")) | None => String::new(),
+        Some((_, exe)) => exe.to_owned(),
+    }
 }
 
-#[derive(Debug, Clone, Default)]
+fn parse_signals(value: &str) -> Result<Vec<u32>, String> {
+    value
+        .split(',')
+        .map(|signal| signal.trim().parse().map_err(|_| format!("invalid signal number '{}'", signal)))
+        .collect()
+}
+
+/// What to do with a core that turns out to be bigger than `set_max_size()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MaxSizeMode {
+    /// Delete the partial file; we don't want it at all.
+    #[default]
+    Delete,
+    /// Keep the first `max_size` bytes and rename it to `<path>.truncated`.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 struct Config {
     output_path: String,
     permissions: u64,
+    /// The mode from the first `set_permissions_for_exe()` that matched, which
+    /// takes precedence over `permissions`.
+    exe_permissions: Option<u64>,
+    /// Add owner read to whatever permissions the outputs get.
+    ensure_owner_read: bool,
+    /// Script-chosen cap on the core size. Unlike `core_limit` this isn't a
+    /// truncation point; exceeding it means the core is rejected.
+    max_size: Option<u64>,
+    max_size_mode: MaxSizeMode,
+    /// Override for the umask used when creating directories. The core file
+    /// itself always ends up with exactly `permissions`.
+    umask: Option<u64>,
+    /// Save `/proc/<pid>/status` and `/proc/<pid>/stat` next to the core.
+    save_proc: bool,
+    /// Save a JSON sidecar with details of the crash as `<core>.json`.
+    save_metadata: bool,
+    /// Retention policy applied after each core is written, and by `gc`.
+    retention: retention::Policy,
+    /// Directory the retention policy applies to. Defaults to the directory
+    /// of the output path.
+    retention_dir: String,
+    /// Maximum total size of the crashing user's cores in the retention
+    /// directory.
+    user_quota_bytes: Option<u64>,
+    /// Set the core's mtime/atime to the crash time rather than when we
+    /// finished writing it.
+    use_crash_time: bool,
+    /// Keep zero-byte cores rather than deleting them.
+    keep_empty: bool,
+    /// Point `<dir>/<exe>.latest` at the newest core.
+    latest_symlink: bool,
+    /// Skip the core if the output filesystem has fewer free inodes.
+    min_free_inodes: Option<u64>,
+    /// Arbitrary key/values for the sidecar.
+    tags: BTreeMap<String, String>,
+    /// Write the core even if the process made itself non-dumpable.
+    force_dump: bool,
+    /// Write as root if the user can't write to the output directory, and
+    /// give them the files afterwards.
+    privileged_write: bool,
+    /// From `set_layout()`. Anything but the default replaces `output_path`
+    /// once the script has finished.
+    layout: layout::Layout,
+    /// Put the outputs in a `boot-<id>` subdirectory of where they'd go.
+    boot_dir: bool,
+    /// Put the outputs in a `<pid % 256>` subdirectory of where they'd go.
+    shard: bool,
+    /// How many directories `boot_dir` and `shard` added between the output
+    /// directory and the cores, which retention and the state files skip.
+    added_dirs: usize,
+    /// From `set_capture_signals()`; overrides `--signals`.
+    capture_signals: Option<Vec<u32>>,
+    /// Always keep this many cores from each executable...
+    keep_first: Option<u64>,
+    /// ...and then one in this many (0 for none).
+    sample_rate: Option<u64>,
+    /// Log a crash storm when an executable crashes this many times a minute.
+    storm_threshold: Option<u64>,
+    /// Only keep one core in every `storm_threshold` during a storm.
+    storm_auto_sample: bool,
+    /// Skip cores from a crash that was captured less than this long ago.
+    debounce_secs: Option<u64>,
+    /// Also write the first this many bytes of the core to `<core>.head`.
+    save_header: u64,
+    /// Write the core as `<core>.000`, `<core>.001`... of up to this many
+    /// bytes each.
+    split_bytes: Option<u64>,
+    /// Save the process's environment in the sidecar.
+    capture_env: bool,
+    /// Save the process's arguments in the sidecar.
+    capture_cmdline: bool,
+    /// Save the process's open fds in the sidecar.
+    save_fds: bool,
+    /// Names to redact as well as `metadata::DEFAULT_REDACT`.
+    redact: Vec<String>,
+    /// Command to run after each core is written.
+    post_hook: Option<String>,
+    /// How long `post_hook` gets. Defaults to `hook::DEFAULT_TIMEOUT`.
+    post_hook_timeout_secs: Option<u64>,
+    /// Extra copies from `add_output()`.
+    extra_outputs: Vec<ExtraOutput>,
+    /// Whether the script called `set_output_path()` or `skip()` at all.
+    #[serde(skip)]
+    output_chosen: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExtraOutput {
+    path: PathBuf,
+    /// Defaults to the `set_permissions()` mode.
+    permissions: Option<u64>,
+}
+
+/// Somewhere to write a copy of the core.
+struct Output {
+    path: PathBuf,
+    permissions: u64,
+}
+
+impl Config {
+    fn retention_dir(&self) -> Option<PathBuf> {
+        if !self.retention_dir.is_empty() {
+            return Some(PathBuf::from(&self.retention_dir));
+        }
+        if self.output_path.is_empty() {
+            return None;
+        }
+        self.output_dir(Path::new(&self.output_path))
+    }
+
+    /// The directory the script put the output at `path` in, before any boot
+    /// or shard directories.
+    fn output_dir(&self, path: &Path) -> Option<PathBuf> {
+        path.ancestors().nth(1 + self.added_dirs).map(Path::to_owned)
+    }
+
+    /// Move every output into a subdirectory `dir` of where it is.
+    fn add_dir(&mut self, dir: &str) {
+        if !self.output_path.is_empty() {
+            self.output_path = in_subdir(Path::new(&self.output_path), dir).to_string_lossy().to_string();
+        }
+        for output in &mut self.extra_outputs {
+            output.path = in_subdir(&output.path, dir);
+        }
+        self.added_dirs += 1;
+    }
+
+    /// Make relative output and retention paths relative to `base` rather
+    /// than our working directory, which is whatever the kernel gave us.
+    fn resolve_relative(&mut self, base: &Path) {
+        let resolve = |path: &mut String| {
+            if !path.is_empty() && Path::new(path).is_relative() {
+                *path = base.join(&*path).to_string_lossy().to_string();
+            }
+        };
+        resolve(&mut self.output_path);
+        resolve(&mut self.retention_dir);
+        for output in &mut self.extra_outputs {
+            if output.path.is_relative() {
+                output.path = base.join(&output.path);
+            }
+        }
+    }
+
+    /// Settings that contradict each other, or do nothing because of another
+    /// one, which are probably mistakes. These aren't errors when handling a
+    /// crash, since the core can still be written, but `doctor` reports them.
+    fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        if !self.save_metadata {
+            let needs_sidecar = [
+                (!self.tags.is_empty(), "set_tag()"),
+                (self.capture_env, "set_capture_env(true)"),
+                (self.capture_cmdline, "set_capture_cmdline(true)"),
+                (self.save_fds, "set_save_fds(true)"),
+                (!self.redact.is_empty(), "set_redact()"),
+            ];
+            for (_, call) in needs_sidecar.iter().filter(|(set, _)| *set) {
+                conflicts.push(format!("{} does nothing without set_save_metadata(true)", call));
+            }
+            // Retention only touches cores it can prove we wrote.
+            if !self.retention.is_empty() || self.user_quota_bytes.is_some() {
+                conflicts.push(
+                    "the retention policy never deletes anything without set_save_metadata(true), since it only \
+                     considers cores with a sidecar"
+                        .to_owned(),
+                );
+            }
+        }
+        if !self.retention_dir.is_empty() && self.retention.is_empty() && self.user_quota_bytes.is_none() {
+            conflicts.push("set_retention_dir() does nothing without a set_max_*() retention policy".to_owned());
+        }
+        if self.storm_auto_sample && self.storm_threshold.is_none() {
+            conflicts.push("set_storm_auto_sample(true) does nothing without set_storm_threshold()".to_owned());
+        }
+        if self.post_hook_timeout_secs.is_some() && self.post_hook.is_none() {
+            conflicts.push("set_post_hook_timeout_secs() does nothing without set_post_hook()".to_owned());
+        }
+        if let (Some(max_size), Some(split_bytes)) = (self.max_size, self.split_bytes) {
+            if split_bytes >= max_size {
+                conflicts.push(format!(
+                    "set_split_bytes({}) never splits anything because cores are limited to set_max_size({})",
+                    split_bytes, max_size
+                ));
+            }
+        }
+        let outputs = self.outputs();
+        for (i, output) in outputs.iter().enumerate() {
+            if outputs[..i].iter().any(|earlier| earlier.path == output.path) {
+                conflicts.push(format!(
+                    "{} is written more than once; check set_output_path() and add_output()",
+                    output.path.display()
+                ));
+            }
+        }
+        conflicts
+    }
+
+    /// Everywhere the core should be written: `output_path` and then anything
+    /// from `add_output()`.
+    fn outputs(&self) -> Vec<Output> {
+        let permissions = self.exe_permissions.unwrap_or(self.permissions);
+        let owner_read = if self.ensure_owner_read { 0o400 } else { 0 };
+        let mut outputs = Vec::new();
+        if !self.output_path.is_empty() {
+            outputs.push(Output {
+                path: PathBuf::from(&self.output_path),
+                permissions: permissions | owner_read,
+            });
+        }
+        outputs.extend(self.extra_outputs.iter().map(|output| Output {
+            path: output.path.clone(),
+            permissions: output.permissions.unwrap_or(permissions) | owner_read,
+        }));
+        outputs
+    }
 }
 
 type SharedConfig = Rc<RefCell<Config>>;
@@ -88,217 +562,3238 @@ fn timestamp() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).expect("It's before 1970!").as_millis()
 }
 
+/// When we started handling the crash, in Unix milliseconds. `%t` is only
+/// whole seconds so this tells apart crashes in the same second, but `%t` is
+/// still the crash time. It's fixed the first time it's asked for.
+fn capture_time_ms() -> u64 {
+    static CAPTURE_TIME: OnceLock<u64> = OnceLock::new();
+    *CAPTURE_TIME.get_or_init(|| timestamp() as u64)
+}
+
+/// What happened to the core. The kernel ignores our exit code but people
+/// and wrapper scripts don't; see the README for the list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Written,
+    /// The config script didn't want it.
+    Skipped,
+    /// The crashed process's core limit was 0 or 1.
+    LimitTooSmall,
+    /// `--max-concurrent` were already running.
+    Overloaded,
+}
+
+impl Outcome {
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Written => 0,
+            Outcome::Skipped => 10,
+            Outcome::LimitTooSmall => 11,
+            Outcome::Overloaded => 13,
+        }
+    }
+}
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_DISK_FULL: i32 = 12;
+const EXIT_TIMEOUT: i32 = 14;
+const EXIT_CONFIG_ERROR: i32 = 20;
+
+/// An error compiling or running the config script.
+#[derive(Debug)]
+struct ConfigError(String);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sellafield config script execution error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn error_exit_code(e: &anyhow::Error) -> i32 {
+    if e.downcast_ref::<ConfigError>().is_some() {
+        return EXIT_CONFIG_ERROR;
+    }
+    let disk_full = e.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            e.raw_os_error() == Some(libc::ENOSPC) || e.kind() == io::ErrorKind::StorageFull
+        })
+    });
+    if disk_full {
+        return EXIT_DISK_FULL;
+    }
+    EXIT_ERROR
+}
+
+/// Whether `e` was caused by the filesystem being read-only, which usually
+/// means the kernel remounted it after an error.
+fn is_read_only(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref::<io::Error>().is_some_and(|e| e.raw_os_error() == Some(libc::EROFS)))
+}
+
 fn main() -> Result<()> {
-    if let Err(e) = run() {
-        // Stdout and Stderr go nowhere when run as a core_pattern so write this to a file.
-        let mut file = fs::File::create(format!("/tmp/sellafield_{}.log", timestamp()))?;
-        writeln!(file, "{}", e)?;
-        return Err(e);
+    // Subcommands are run by people so they don't need the log file.
+    if std::env::args().nth(1).is_some_and(|arg| !arg.starts_with('-')) {
+        return run_tool();
     }
-    Ok(())
+
+    let code = match run() {
+        Ok(outcome) => outcome.exit_code(),
+        Err(e) => {
+            log(&format!("{}", e))?;
+            eprintln!("Error: {:?}", e);
+            error_exit_code(&e)
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Stdout and Stderr go nowhere when run as a core_pattern so we log to
+/// `/tmp/sellafield_<epoch millis>.log` instead. The file is only created if
+/// something is actually logged.
+fn log(message: &str) -> io::Result<()> {
+    static LOG_PATH: OnceLock<String> = OnceLock::new();
+    let path = LOG_PATH.get_or_init(|| format!("/tmp/sellafield_{}.log", timestamp()));
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    writeln!(file, "{}", message)
+}
+
+/// Log something that went wrong but isn't worth failing for.
+fn warn(message: &str) {
+    // There's nowhere else to report it if this fails.
+    let _ = log(&format!("Warning: {}", message));
+}
+
+/// Log to syslog (and therefore journald). Unlike `log()`, this is for things
+/// operators want to see, rather than just for debugging.
+fn syslog(message: &str) {
+    syslog_with_priority(libc::LOG_INFO, message);
+}
+
+/// Log to syslog at warning priority, for things that need someone's
+/// attention.
+fn syslog_warning(message: &str) {
+    syslog_with_priority(libc::LOG_WARNING, message);
+}
+
+fn syslog_with_priority(priority: libc::c_int, message: &str) {
+    let message = match CString::new(message) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    unsafe {
+        libc::openlog(c"sellafield".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON);
+        libc::syslog(priority, c"%s".as_ptr(), message.as_ptr());
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+const PROGRESS_BYTES: u64 = 1_000_000_000;
+
+/// Periodic progress logging for `--progress`.
+struct Progress {
+    pid: u32,
+    start: Instant,
+    last_time: Instant,
+    last_bytes: u64,
+}
+
+impl Progress {
+    fn new(pid: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            pid,
+            start: now,
+            last_time: now,
+            last_bytes: 0,
+        }
+    }
+
+    /// Called after each chunk with the total written so far. This is cheap
+    /// unless it's time to log.
+    fn update(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let elapsed = now - self.last_time;
+        if elapsed < PROGRESS_INTERVAL && bytes - self.last_bytes < PROGRESS_BYTES {
+            return;
+        }
+        syslog(&format!(
+            "writing core for process {}: {} MB so far, {:.1} MB/s",
+            self.pid,
+            bytes / 1_000_000,
+            mb_per_sec(bytes - self.last_bytes, elapsed)
+        ));
+        self.last_time = now;
+        self.last_bytes = bytes;
+    }
+
+    fn finish(&self, bytes: u64) {
+        let elapsed = self.start.elapsed();
+        syslog(&format!(
+            "finished core for process {}: {} MB in {:.1} s, {:.1} MB/s",
+            self.pid,
+            bytes / 1_000_000,
+            elapsed.as_secs_f64(),
+            mb_per_sec(bytes, elapsed)
+        ));
+    }
+}
+
+/// A small core can be written in no measurable time, which would make the
+/// rate inf (or NaN for nothing at all).
+fn mb_per_sec(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / 1e6 / elapsed.max(Duration::from_millis(1)).as_secs_f64()
+}
+
+/// Periodic messages for `--heartbeat-secs`. They're tagged with the pid and
+/// crash time so all the messages for one crash can be found.
+struct Heartbeat {
+    id: String,
+    interval: Duration,
+    start: Instant,
+    last: Instant,
+}
+
+impl Heartbeat {
+    fn new(opts: &Opts) -> Option<Self> {
+        let interval = Duration::from_secs(opts.heartbeat_secs.filter(|&secs| secs > 0)?);
+        let now = Instant::now();
+        Some(Self {
+            id: format!("{}.{}", opts.pid, opts.time),
+            interval,
+            start: now,
+            last: now,
+        })
+    }
+
+    /// Log `phase` if it's been `interval` since the last one. Cheap otherwise.
+    fn beat(&mut self, phase: impl FnOnce() -> String) {
+        let now = Instant::now();
+        if now - self.last < self.interval {
+            return;
+        }
+        syslog(&format!(
+            "heartbeat {}: {} ({:.0} s)",
+            self.id,
+            phase(),
+            (now - self.start).as_secs_f64()
+        ));
+        self.last = now;
+    }
+}
+
+/// Set by `--verbose`. It's global so the script functions can see it.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Log a step if `--verbose` was given.
+fn trace(message: &str) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        let _ = log(&format!("Trace: {}", message));
+    }
+}
+
+fn run_tool() -> Result<()> {
+    let tool: Tool = match try_from_env() {
+        Ok(tool) => tool,
+        Err(early_exit) => {
+            if early_exit.status.is_ok() {
+                println!("{}", early_exit.output);
+                return Ok(());
+            }
+            eprintln!("{}", early_exit.output);
+            std::process::exit(1);
+        }
+    };
+
+    match tool.command {
+        ToolCommand::Install(opts) => install::install(&opts),
+        ToolCommand::Uninstall(opts) => install::uninstall(&opts),
+        ToolCommand::Prune(opts) => retention::prune(&opts),
+        ToolCommand::Gc(opts) => retention::gc(&opts),
+        ToolCommand::Show(opts) => metadata::show(&opts),
+        ToolCommand::Export(opts) => export::export(&opts),
+        ToolCommand::BuildId(opts) => elf::print_build_id(&opts),
+        ToolCommand::Status(opts) => install::status(&opts),
+        ToolCommand::Doctor(opts) => install::doctor(&opts),
+        ToolCommand::Bench(opts) => bench::bench(&opts),
+        ToolCommand::Reassemble(opts) => reassemble::reassemble(&opts),
+        ToolCommand::Cat(opts) => cat::cat(&opts),
+        ToolCommand::ResetCounter(opts) => counter::reset(&opts),
+        ToolCommand::Simulate(opts) => simulate::simulate(&opts),
+        ToolCommand::Api(opts) => api::api(&opts),
+    }
+}
+
+fn run() -> Result<Outcome> {
+    let args = unpack_args(std::env::args().collect())?;
+    let opts: Opts = try_from_args(&args).map_err(|e| anyhow!("{}", e.output))?;
+    handle_crash(&opts)
+}
+
+/// The options that `--packed` contains, in order. `-E` is last because it's
+/// the only one that could contain `:`.
+const PACKED_OPTIONS: &[&str] = &["-u", "-p", "-t", "-c", "-d", "-E"];
+pub const PACKED_TEMPLATE: &str = "%u:%p:%t:%c:%d:%E";
+
+/// Add the individual options for `--packed`/`-P` to `args`, so they get
+/// parsed and checked normally.
+fn unpack_args(mut args: Vec<String>) -> Result<Vec<String>> {
+    let packed = match args.iter().position(|arg| arg == "--packed" || arg == "-P") {
+        Some(i) => match args.get(i + 1) {
+            Some(packed) => packed.clone(),
+            // Let argh complain about it.
+            None => return Ok(args),
+        },
+        None => return Ok(args),
+    };
+    let values: Vec<&str> = packed.splitn(PACKED_OPTIONS.len(), ':').collect();
+    if values.len() != PACKED_OPTIONS.len() {
+        bail!(
+            "--packed '{}' has {} fields but should have {}: {}",
+            packed,
+            values.len(),
+            PACKED_OPTIONS.len(),
+            PACKED_TEMPLATE
+        );
+    }
+    for (option, value) in PACKED_OPTIONS.iter().zip(values) {
+        args.push(option.to_string());
+        args.push(value.to_owned());
+    }
+    Ok(args)
 }
 
-fn run() -> Result<()> {
-    let opts: Opts = try_from_env().map_err(|e| anyhow!("{}", e.output))?;
+fn handle_crash(opts: &Opts) -> Result<Outcome> {
+    if opts.verbose {
+        VERBOSE.store(true, Ordering::Relaxed);
+    }
+    trace(&format!("{:?}", opts));
+    capture_time_ms();
+
+    if opts.deadline > 0 {
+        start_watchdog(Duration::from_secs(opts.deadline));
+    }
+
+    let mut input: Box<dyn Read> = match &opts.input {
+        Some(path) => Box::new(BufReader::new(fs::File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
 
     // The kernel determines the minimum size for the specific output format
     // (e.g. 4kB for ELF), but we'll just do something simpler.
     // 1 is some kind of special value so exclude that too.
     if opts.core_limit <= 1 {
-        return Ok(());
+        trace("core limit too small; skipping");
+        drain_on_skip(opts, &mut input);
+        return Ok(Outcome::LimitTooSmall);
     }
 
-    // This runs as root by default, but we want to drop permissions to the
-    // given UID.
-    set_uid(opts.uid)?;
-
-    // Wrangle the exe name which has / replaced with !. Better hope nobody puts
-    // ! in their filenames!
-    let full_exe = opts.exe.replace('!', "/");
-    let exe = full_exe
-        .rsplit_once('/')
-        .and_then(|(_, exe)| Some(exe))
-        .unwrap_or_default()
-        .to_owned();
+    // Held until we exit. This has to be before we drop privileges since the
+    // lock directory belongs to root.
+    let _slot = if opts.max_concurrent > 0 {
+        match slots::acquire(&opts.lock_dir, opts.max_concurrent, opts.on_overload) {
+            Ok(Some(slot)) => Some(slot),
+            Ok(None) => {
+                warn(&format!(
+                    "{} sellafields are already running; skipping core for process {}",
+                    opts.max_concurrent, opts.pid
+                ));
+                drain_on_skip(opts, &mut input);
+                return Ok(Outcome::Overloaded);
+            }
+            // Better to write the core than lose it because of the limit.
+            Err(e) => {
+                warn(&format!("not limiting concurrency: {:#}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Also opened before dropping privileges. This core is counted now and
+    // given back (when the reservation is dropped) if it isn't written.
+    let mut reservation = None;
+    if let Some(max) = opts.max_total_dumps {
+        match counter::Counter::open(&opts.lock_dir).and_then(|counter| counter.reserve(max)) {
+            Ok(counter::Reserve::Full(total)) => {
+                warn(&format!(
+                    "{} cores written, which is the --max-total-dumps limit; skipping core for process {}. \
+                     Run `sellafield reset-counter` to start writing them again.",
+                    total, opts.pid
+                ));
+                drain_on_skip(opts, &mut input);
+                return Ok(Outcome::Skipped);
+            }
+            Ok(counter::Reserve::Reserved(reserved)) => reservation = Some(reserved),
+            Err(e) => warn(&format!("not limiting the total number of cores: {:#}", e)),
+        }
+    }
+
+    // The shared state files are in the lock directory too. Whether they're
+    // needed isn't known until the script has run, so errors only matter
+    // then.
+    let mut samples = state::StateFile::open(&opts.lock_dir, sampling::STATE_FILE);
+    let mut storms = state::StateFile::open(&opts.lock_dir, storm::STATE_FILE);
+    let mut debounces = state::StateFile::open(&opts.lock_dir, debounce::STATE_FILE);
+
+    // Snapshot the process while we can still read everything.
+    let process = ProcessInfo::read(opts.pid).unwrap_or_else(|| {
+        warn("/proc isn't mounted; the script and sidecar won't have the process's details");
+        ProcessInfo::default()
+    });
 
     // Get username & home directory.
     let user_details = get_user_details(opts.uid)?;
+    trace(&format!(
+        "user {} home {} gid {}",
+        user_details.username,
+        user_details.home.display(),
+        user_details.gid
+    ));
+
+    // This runs as root by default, but we want to drop permissions to the
+    // given user (or --output-uid), so everything we create belongs to them.
+    // Root is kept as the saved uid until we know whether the script wants a
+    // privileged write.
+    let (owner_uid, owner) = output_owner(opts, &user_details)?;
+    drop_privileges(owner_uid, &owner, true)?;
+
+    let full_exe = decode_exe(&opts.exe, opts.exe_encoding);
+    let exe = exe_name(&full_exe);
 
     // Run the config script to find the output path.
-    let config = run_script(&opts, &full_exe, &exe, &user_details)?;
+    let mut config = match run_script(opts, &full_exe, &exe, &user_details, &process) {
+        Ok(config) => config,
+        Err(e) => {
+            // A broken script would lose every core, so this one doesn't need
+            // --fallback-dir.
+            let dir = script_fallback_dir(opts, Path::new(DEFAULT_FALLBACK_DIR));
+            write_fallback(opts, dir.as_deref(), &mut input, &format!("{:#}", e));
+            return Err(e);
+        }
+    };
 
-    if !config.output_path.is_empty() {
-        // Copy stdin to the output path and set permissions.
-        write_output(&config, opts.core_limit)?;
+    if !config.privileged_write {
+        drop_saved_privileges(owner_uid)?;
     }
 
-    Ok(())
-}
+    config.resolve_relative(opts.base_dir.as_deref().unwrap_or(&user_details.home));
 
-fn run_script(opts: &Opts, full_exe: &str, exe: &str, user_details: &UserDetails) -> Result<Config> {
+    // Service accounts often have a home directory that doesn't exist. If the
+    // script wants to write there anyway use the fallback directory instead.
+    let output_path = Path::new(&config.output_path);
+    if let Some(fallback) = home_fallback_path(output_path, &user_details.home, opts.fallback_dir.as_deref()) {
+        warn(&format!(
+            "home directory {} doesn't exist or isn't writable; writing core to {} instead of {}",
+            user_details.home.display(),
+            fallback.display(),
+            config.output_path
+        ));
+        config.output_path = fallback.to_string_lossy().to_string();
+    }
 
-    let mut engine = Engine::new();
-    // We're only executing the script once so don't bother optimising it.
-    engine.set_optimization_level(OptimizationLevel::None);
+    trace(&format!("config {}", serde_json::to_string(&config)?));
 
-    // TODO: Sort out encodings. This is all wrong.
-    let home = user_details.home.to_string_lossy().to_string();
-    let username = user_details.username.clone();
-    let uid = opts.uid;
-    let pid = opts.pid;
-    let time = opts.time;
-    let full_exe = full_exe.to_owned();
-    let exe = exe.to_owned();
+    // Probably a typo, e.g. 0o044 for 0o644, but it might be deliberate.
+    for output in config.outputs() {
+        if output.permissions & 0o400 == 0 {
+            warn(&format!(
+                "{} will have mode {:#o} so its owner can't read it; use set_ensure_owner_read(true) if that's \
+                 a mistake",
+                output.path.display(),
+                output.permissions
+            ));
+        }
+    }
 
-    // Functions to get various details.
-    engine.register_fn("home", move || home.clone());
-    engine.register_fn("username", move || username.clone());
-    engine.register_fn("uid", move || uid);
-    engine.register_fn("pid", move || pid);
-    engine.register_fn("time", move || time);
-    engine.register_fn("full_exe", move || full_exe.clone());
-    engine.register_fn("exe", move || exe.clone());
+    if let Some(path) = &opts.emit_config_json {
+        let file = fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, &config).context("error writing config JSON")?;
+    }
 
-    // Config-setting functions.
-    let config = SharedConfig::default();
-    // Set sensible default.
-    config.borrow_mut().permissions = 0o400u64;
+    // With a fallback directory or --require-output, forgetting to set the
+    // path is treated as a mistake. skip() or set_output_path("") still skip it.
+    if config.outputs().is_empty() && !config.output_chosen {
+        let reason = "config script didn't call set_output_path() or skip()";
+        let written = write_fallback(opts, opts.fallback_dir.as_deref(), &mut input, reason);
+        if opts.require_output {
+            if !written {
+                drain_on_skip(opts, &mut input);
+            }
+            return Err(ConfigError(reason.to_owned()).into());
+        }
+        if written {
+            return Ok(Outcome::Written);
+        }
+    }
 
-    let cfg = config.clone();
-    engine.register_fn("set_output_path", move |x| cfg.borrow_mut().output_path = x);
-    let cfg = config.clone();
-    engine.register_fn("set_permissions", move |x: i64| cfg.borrow_mut().permissions = x as u64);
+    if config.outputs().is_empty() {
+        trace("no outputs; skipping");
+        drain_on_skip(opts, &mut input);
+        return Ok(Outcome::Skipped);
+    }
 
-    let mut scope = Scope::new();
+    // Processes that set PR_SET_DUMPABLE to 0 (or run setuid) are only dumped
+    // because suid_dumpable is 2, and probably have secrets in memory.
+    if opts.dump_mode.is_some_and(|mode| mode != 1) && !config.force_dump {
+        warn(&format!(
+            "process {} ({}) is not dumpable (dump mode {}); not writing {}. \
+             Use set_force_dump(true) to write it anyway.",
+            opts.pid,
+            full_exe,
+            opts.dump_mode.unwrap_or_default(),
+            config.output_path
+        ));
+        drain_on_skip(opts, &mut input);
+        return Ok(Outcome::Skipped);
+    }
 
-    // Not sure why you can't use .context() here. It gives threading errors.
-    engine
-        .eval_file_with_scope::<()>(&mut scope, opts.config.clone())
-        .map_err(|e| anyhow!("Sellafield config script execution error: {}", e))?;
+    // If we don't know the signal there's nothing to filter on.
+    let capture_signals = config.capture_signals.as_ref().or(opts.signals.as_ref());
+    if let (Some(signal), Some(signals)) = (opts.signal, capture_signals) {
+        if !signals.contains(&signal) {
+            trace(&format!("signal {} isn't one of {:?}; skipping", signal, signals));
+            drain_on_skip(opts, &mut input);
+            return Ok(Outcome::Skipped);
+        }
+    }
 
-    // Clone the config for simplicity.
-    let config = config.borrow().clone();
-    Ok(config)
-}
+    let debounce_key = debounce::key(&full_exe, process.build_id.as_deref());
 
-fn write_output(config: &Config, core_limit: u64) -> Result<()> {
-    // Set the umask otherwise it creates directories that are world-writable.
-    set_umask(0o022);
+    // Before debouncing and sampling so every crash counts.
+    if let Some(threshold) = config.storm_threshold {
+        let storm = storms
+            .as_mut()
+            .map_err(|e| anyhow!("{:#}", e))
+            .and_then(|state| storm::record(state, &full_exe, opts.time.into(), threshold));
+        match storm {
+            Ok(storm::Storm::Storm { rate, crashes, sample }) => {
+                if crashes == 0 {
+                    syslog_warning(&format!("crash storm: {} {}/min", full_exe, rate));
+                }
+                // Keep one a minute.
+                if config.storm_auto_sample && !sample {
+                    trace(&format!("{} is in a crash storm ({}/min); not sampled", full_exe, rate));
+                    drain_on_skip(opts, &mut input);
+                    return Ok(Outcome::Skipped);
+                }
+            }
+            Ok(storm::Storm::Calm) => {}
+            Err(e) => warn(&format!("error checking for a crash storm: {:#}", e)),
+        }
+    }
+    if let Some(secs) = config.debounce_secs {
+        let recent = debounces
+            .as_mut()
+            .map_err(|e| anyhow!("{:#}", e))
+            .and_then(|state| debounce::recently_captured(state, &debounce_key, opts.time.into(), secs));
+        match recent {
+            Ok(true) => {
+                trace(&format!("{} was captured less than {} seconds ago; skipping", debounce_key, secs));
+                drain_on_skip(opts, &mut input);
+                return Ok(Outcome::Skipped);
+            }
+            Ok(false) => {}
+            Err(e) => warn(&format!("error checking for a recent capture: {:#}", e)),
+        }
+    }
 
-    let output_path = Path::new(&config.output_path);
+    if config.keep_first.is_some() || config.sample_rate.is_some() {
+        let keep_first = config.keep_first.unwrap_or(0);
+        let sample_rate = config.sample_rate.unwrap_or(0);
+        // Err on the side of keeping it if the count isn't available.
+        let count = samples
+            .as_mut()
+            .map_err(|e| anyhow!("{:#}", e))
+            .and_then(|state| sampling::count(state, &full_exe));
+        match count {
+            Ok(count) if !sampling::keep(count, keep_first, sample_rate) => {
+                trace(&format!("{} has crashed {} times before; not sampled", full_exe, count));
+                drain_on_skip(opts, &mut input);
+                return Ok(Outcome::Skipped);
+            }
+            Ok(_) => {}
+            Err(e) => warn(&format!("error counting crashes: {:#}", e)),
+        }
+    }
 
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent)?;
+    // Only get root back if the user really can't write there.
+    let privileged = config.privileged_write
+        && config.outputs().iter().any(|output| !is_writable_dir(&existing_ancestor(&output.path)))
+        && match set_effective_uid(0) {
+            Ok(()) => true,
+            Err(e) => {
+                warn(&format!("can't write as root: {:#}", e));
+                false
+            }
+        };
+    if privileged {
+        trace("user can't write to the output directory; writing as root");
     }
 
-    let permissions_mode: u16 = config.permissions.try_into().map_err(|e| {
-        anyhow!(
-            "Sellafield config script returned invalid permissions mode: {}. {}",
-            config.permissions,
-            e
-        )
-    })?;
+    // Copy stdin to the output paths and set permissions.
+    let cores = match write_output(&config, opts, &mut input) {
+        Ok(cores) => cores,
+        Err(e) => {
+            write_fallback(opts, opts.fallback_dir.as_deref(), &mut input, &format!("{:#}", e));
+            return Err(e);
+        }
+    };
+    for core in &cores {
+        trace(&format!("wrote {} bytes to {}", core.bytes, core.path.display()));
+    }
 
-    // Set umask so we can set the requested bits of permissions_mode.
-    set_umask(!(permissions_mode as libc::mode_t));
+    if cores.is_empty() {
+        trace("no cores kept");
+        drain_on_skip(opts, &mut input);
+    }
 
-    // Write stdin to a file.
-    // This has not been added to fs_err.
-    // let out = File::options()
-    let out = fs::OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(&output_path)?;
-    let mut out_writer = BufWriter::new(out);
+    // Uncounted if nothing was written.
+    if let Some(total) = reservation.filter(|_| !cores.is_empty()).map(counter::Reservation::keep) {
+        if Some(total) == opts.max_total_dumps {
+            syslog(&format!(
+                "{} cores written, which is the --max-total-dumps limit; no more will be written until \
+                 `sellafield reset-counter` is run",
+                total
+            ));
+        }
+    }
+
+    if let (Some(secs), Ok(state), false) = (config.debounce_secs, &mut debounces, cores.is_empty()) {
+        if let Err(e) = debounce::record(state, &debounce_key, opts.time.into(), secs) {
+            warn(&format!("error updating debounce state: {:#}", e));
+        }
+    }
+
+    // The core is safe by now, so failing to write the extras around it
+    // only warns.
+    for core in &cores {
+        if config.save_proc {
+            if let Err(e) = write_proc_files(core, &process) {
+                warn(&format!("error writing /proc files: {:#}", e));
+            }
+        }
+        if config.save_header > 0 {
+            if let Err(e) = write_header(core) {
+                warn(&format!("error writing header: {:#}", e));
+            }
+        }
+        if config.latest_symlink && !exe.is_empty() {
+            if let Err(e) = update_latest_symlink(&core.files()[0], &exe, opts.pid) {
+                warn(&format!("error updating latest symlink: {:#}", e));
+            }
+        }
+        if config.save_metadata {
+            let metadata = Metadata {
+                uid: opts.uid,
+                username: user_details.username.clone(),
+                pid: opts.pid,
+                time: opts.time,
+                capture_time_ms: Some(capture_time_ms()),
+                exe: exe.clone(),
+                full_exe: full_exe.clone(),
+                core_limit: opts.core_limit,
+                core_bytes: core.bytes,
+                truncated: core.truncated,
+                tags: config.tags.clone(),
+                context: metadata::wrapper_context(std::env::vars_os()),
+                chunks: core.chunks,
+                siginfo: core.siginfo.clone(),
+                arch: core.arch.clone(),
+                shard: config.shard.then(|| shard_name(opts.pid)),
+                env: config.capture_env.then(|| {
+                    let vars = process::split_nul(process.environ.as_deref().unwrap_or_default());
+                    metadata::redact_env(&vars, &config.redact)
+                }),
+                cmdline: config.capture_cmdline.then(|| {
+                    let args = process::split_nul(process.cmdline.as_deref().unwrap_or_default());
+                    metadata::redact_cmdline(&args, &config.redact)
+                }),
+                fds: process.fds.clone().filter(|_| config.save_fds),
+            };
+            if let Err(e) = write_metadata(core, &metadata) {
+                warn(&format!("error writing metadata: {:#}", e));
+            }
+        }
+    }
 
-    // Set file permissions. This may not be strictly necessary because of the
-    // set_umask() call above but just to be sure...
-    let permissions = make_permissions(permissions_mode as u32);
-    fs::set_permissions(&output_path, permissions)
-        .context("error setting core dump permissions")?;
+    if privileged {
+        for core in &cores {
+            give_to_user(core, owner_uid, owner.gid);
+        }
+    }
+    // Nothing after this needs root, and the post hook mustn't be able to get
+    // it back.
+    if config.privileged_write {
+        drop_saved_privileges(owner_uid)?;
+    }
 
-    // Write stdin out to that file.
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
+    for core in &cores {
+        if let Some(command) = &config.post_hook {
+            let timeout = config.post_hook_timeout_secs.map_or(hook::DEFAULT_TIMEOUT, Duration::from_secs);
+            let mut env = vec![
+                ("SELLAFIELD_CORE", core.path.to_string_lossy().to_string()),
+                ("SELLAFIELD_BYTES", core.bytes.to_string()),
+                ("SELLAFIELD_UID", opts.uid.to_string()),
+                ("SELLAFIELD_PID", opts.pid.to_string()),
+                ("SELLAFIELD_TIME", opts.time.to_string()),
+                ("SELLAFIELD_EXE", full_exe.clone()),
+            ];
+            if config.save_metadata {
+                env.push(("SELLAFIELD_METADATA", with_suffix(&core.path, ".json").to_string_lossy().to_string()));
+            }
+            // The core's fine either way.
+            if let Err(e) = hook::run(command, &core.path, &env, timeout) {
+                warn(&format!("{:#}", e));
+            }
+        }
+    }
 
-    io::copy(&mut stdin.by_ref().take(core_limit), &mut out_writer)
-        .context("error writing core dump")?;
+    // The core is already safe, so like the hooks these only warn.
+    if let Some(dir) = config.retention_dir() {
+        if !config.retention.is_empty() {
+            if let Err(e) = retention::apply(&dir, &config.retention, retention::now(), false) {
+                warn(&format!("retention: {:#}", e));
+            }
+        }
+        // We've dropped to the user's uid by now so this can only delete
+        // their own files.
+        if let Some(quota) = config.user_quota_bytes {
+            if let Err(e) = retention::apply_user_quota(&dir, opts.uid, quota) {
+                warn(&format!("user quota: {:#}", e));
+            }
+        }
+    }
 
-    Ok(())
+    Ok(if cores.is_empty() { Outcome::Skipped } else { Outcome::Written })
 }
 
-struct UserDetails {
-    username: String,
-    home: PathBuf,
+/// Where to write the core if the script fails without a `--fallback-dir`,
+/// if the user can write there.
+const DEFAULT_FALLBACK_DIR: &str = "/var/crash";
+
+/// `--fallback-dir`, or `default` if there isn't one and the user can write
+/// to it.
+fn script_fallback_dir(opts: &Opts, default: &Path) -> Option<PathBuf> {
+    opts.fallback_dir.clone().or_else(|| is_writable_dir(default).then(|| default.to_owned()))
 }
 
-#[cfg(unix)]
-fn get_user_details(uid: u32) -> Result<UserDetails> {
-    set_errno(Errno(0));
-    let passwd = unsafe { libc::getpwuid(uid) };
-    if passwd.is_null() {
-        bail!("Error getting username for user ID {}: {}", uid, errno());
+/// Last resort if there's a fallback directory and something went wrong:
+/// write whatever is left of the core to
+/// `<dir>/core.<pid>.<time>.<capture time in ms>`, so a config bug doesn't
+/// lose it. Returns whether it was written.
+fn write_fallback(opts: &Opts, dir: Option<&Path>, input: &mut impl Read, reason: &str) -> bool {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return false,
+    };
+    let config = Config {
+        output_path: dir
+            .join(format!("core.{}.{}.{}", opts.pid, opts.time, capture_time_ms()))
+            .to_string_lossy()
+            .to_string(),
+        permissions: 0o600,
+        ..Default::default()
+    };
+    match write_output(&config, opts, input) {
+        Ok(_) => {
+            warn(&format!("{}; wrote what was left of the core to {}", reason, config.output_path));
+            true
+        }
+        Err(e) => {
+            warn(&format!("{}; error writing core to fallback directory: {:#}", reason, e));
+            false
+        }
     }
+}
 
-    let pw_name_cstr: &CStr = unsafe { CStr::from_ptr((*passwd).pw_name) };
-    let pw_name = latin1_to_string(pw_name_cstr.to_bytes());
+/// Run the config script with dummy crash details, for commands that only
+/// need the policy settings from it.
+fn run_policy_script(config: &Path) -> Result<Config> {
+    let uid = unsafe { libc::getuid() };
+    let opts = Opts {
+        uid,
+        time: retention::now() as u32,
+        config: config.to_owned(),
+        ..Default::default()
+    };
+    let user_details = get_user_details(uid)?;
+    run_script(&opts, "", "", &user_details, &ProcessInfo::default())
+}
 
-    let pw_dir_cstr: &CStr = unsafe { CStr::from_ptr((*passwd).pw_dir) };
-    let pw_dir = latin1_to_path(pw_dir_cstr.to_bytes());
+/// Don't read more than this when draining stdin. It's just to be polite
+/// to the kernel so there's no point spending ages on it.
+const DRAIN_LIMIT: u64 = 16 * 1024 * 1024 * 1024;
 
-    Ok(UserDetails {
-        username: pw_name,
-        home: pw_dir,
-    })
+/// Exit after `deadline` even if the main thread is stuck (e.g. on a hung NFS
+/// mount), deleting any partial cores so they aren't mistaken for complete
+/// ones.
+fn start_watchdog(deadline: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(deadline);
+        // Keep the lock so nothing else gets added while we exit.
+        let partial = PARTIAL_FILES.lock().unwrap_or_else(|e| e.into_inner());
+        for path in partial.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+        warn(&format!(
+            "timed out after {} seconds; deleted partial cores: {:?}",
+            deadline.as_secs(),
+            *partial
+        ));
+        std::process::exit(EXIT_TIMEOUT);
+    });
 }
 
-#[cfg(unix)]
-fn set_uid(uid: u32) -> Result<()> {
-    // This is not strictly necessary in this case, but you can't be too
-    // safe when dealing with C.
-    set_errno(Errno(0));
-    let rc = unsafe { libc::setuid(uid) };
-    if rc != 0 {
-        bail!("Error switching to user ID {}: {}", uid, errno());
+/// Read the rest of the core when we've decided not to write it. If we exit
+/// without reading it the kernel gets a broken pipe, which is normally fine
+/// but some tools complain about.
+fn drain_on_skip(opts: &Opts, input: &mut impl Read) {
+    if opts.no_drain_on_skip {
+        return;
     }
-    Ok(())
+    // We're not writing anything so errors don't matter. Anything already
+    // in stdin's buffer has been taken out of the pipe, so it can be
+    // drained directly.
+    let _ = match opts.input {
+        Some(_) => drain::read_and_discard(input, DRAIN_LIMIT),
+        None => drain::drain(libc::STDIN_FILENO, DRAIN_LIMIT),
+    };
 }
 
-#[cfg(unix)]
-fn set_umask(mask: libc::mode_t) {
-    // This always succeeds.
-    unsafe {
-        libc::umask(mask);
+/// If `output_path` is under `home` but `home` is missing or not writable,
+/// return where to write the core in `fallback_dir` instead.
+fn home_fallback_path(output_path: &Path, home: &Path, fallback_dir: Option<&Path>) -> Option<PathBuf> {
+    let fallback_dir = fallback_dir?;
+    if !output_path.starts_with(home) || is_writable_dir(home) {
+        return None;
     }
+    Some(fallback_dir.join(output_path.file_name()?))
 }
 
-fn latin1_to_string(s: &[u8]) -> String {
-    s.iter().map(|&c| c as char).collect()
-}
-
-fn latin1_to_path(s: &[u8]) -> PathBuf {
-    let os_str = OsStr::from_bytes(s);
-    PathBuf::from(os_str)
+/// Whether `path` is a directory we can write to as the current (real) user.
+fn is_writable_dir(path: &Path) -> bool {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+    path.is_dir() && unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } == 0
 }
 
-#[cfg(unix)]
-fn make_permissions(mode: u32) -> std::fs::Permissions {
-    use std::os::unix::prelude::PermissionsExt;
+fn run_script(
+    opts: &Opts,
+    full_exe: &str,
+    exe: &str,
+    user_details: &UserDetails,
+    process: &ProcessInfo,
+) -> Result<Config> {
+    let (engine, mut scope, config) = script_engine(opts, full_exe, exe, user_details, process);
 
-    PermissionsExt::from_mode(mode)
+    // Not sure why you can't use .context() here. It gives threading errors.
+    if opts.config.extension().is_some_and(|ext| ext == "gz") {
+        let script = read_gzip_config(&opts.config)?;
+        let mut ast = engine.compile(script).map_err(|e| ConfigError(e.to_string()))?;
+        ast.set_source(opts.config.to_string_lossy().as_ref());
+        engine.eval_ast_with_scope::<()>(&mut scope, &ast).map_err(|e| ConfigError(e.to_string()))?;
+    } else {
+        engine
+            .eval_file_with_scope::<()>(&mut scope, opts.config.clone())
+            .map_err(|e| ConfigError(e.to_string()))?;
+    }
+
+    // Clone the config for simplicity.
+    let mut config = config.borrow().clone();
+    if config.layout == layout::Layout::Systemd {
+        let comm = process.comm().unwrap_or_else(|| truncate_comm(exe));
+        let boot_id = kernel::boot_id().unwrap_or_else(|| {
+            warn("can't read the boot ID for set_layout(\"systemd\")");
+            "0".repeat(32)
+        });
+        let name = layout::systemd_core_name(&comm, opts.uid, &boot_id, opts.pid, opts.time);
+        config.output_path = Path::new(layout::SYSTEMD_COREDUMP_DIR).join(name).to_string_lossy().to_string();
+    }
+    if config.boot_dir {
+        match kernel::boot_id() {
+            Some(boot_id) => config.add_dir(&format!("boot-{}", boot_id)),
+            None => warn("can't read the boot ID for set_boot_dir(true); writing outside a boot directory"),
+        }
+    }
+    if config.shard {
+        config.add_dir(&shard_name(opts.pid));
+    }
+    Ok(config)
+}
+
+/// The `set_shard()` directory for a core: the PID modulo 256 as two hex
+/// digits.
+fn shard_name(pid: u32) -> String {
+    format!("{:02x}", pid % 256)
+}
+
+/// The engine for the config script, with all our functions registered and
+/// the crash details in its scope, and the config the script changes.
+fn script_engine(
+    opts: &Opts,
+    full_exe: &str,
+    exe: &str,
+    user_details: &UserDetails,
+    process: &ProcessInfo,
+) -> (Engine, Scope<'static>, SharedConfig) {
+    let mut engine = Engine::new();
+    // We're only executing the script once so don't bother optimising it.
+    engine.set_optimization_level(OptimizationLevel::None);
+
+    if let Some(heartbeat) = Heartbeat::new(opts) {
+        let heartbeat = RefCell::new(heartbeat);
+        engine.on_progress(move |_| {
+            heartbeat.borrow_mut().beat(|| "running config script".to_owned());
+            None
+        });
+    }
+
+    // TODO: Sort out encodings. This is all wrong.
+    let home = user_details.home.to_string_lossy().to_string();
+    let username = user_details.username.clone();
+    // Rhai can't compare u32 with integer literals so use its native type.
+    let uid = opts.uid as i64;
+    let pid = opts.pid as i64;
+    let time = opts.time as i64;
+    // -1 if it wasn't passed.
+    let dump_mode = opts.dump_mode.map(i64::from).unwrap_or(-1);
+    let signal = opts.signal.map(i64::from).unwrap_or(-1);
+    let full_exe = full_exe.to_owned();
+    let exe = exe.to_owned();
+    let kernel_filename = opts.kernel_filename.clone().unwrap_or_default();
+    // Without -e the main thread's is the next best thing.
+    let comm_value = opts.comm.clone().or_else(|| process.comm()).unwrap_or_default();
+    // Falls back to the real user if the process has gone.
+    let effective_uid = process.effective_uid().unwrap_or(opts.uid);
+    let effective_username = if effective_uid == opts.uid {
+        username.clone()
+    } else {
+        get_user_details(effective_uid).map(|details| details.username).unwrap_or_default()
+    };
+    let effective_uid = effective_uid as i64;
+    let real_exe = process.exe.as_ref().map(|exe| exe.to_string_lossy().to_string()).unwrap_or_default();
+    let exe_deleted = process.exe_deleted;
+    let process_uptime_secs = process.uptime_secs().map(|secs| secs as i64).unwrap_or(-1);
+    let cgroup = process
+        .cgroup
+        .as_ref()
+        .map(|cgroup| process::cgroup_path(&String::from_utf8_lossy(cgroup)))
+        .unwrap_or_default();
+    let container_id = process::container_id(&cgroup);
+    let home_exists = user_details.home.is_dir();
+    let groups_username = user_details.username.clone();
+    let gid = user_details.gid;
+    let args = process::split_nul(process.cmdline.as_deref().unwrap_or_default());
+    let script_name = process::script_name(&exe, &args).unwrap_or_else(|| exe.clone());
+    let rule_exe = exe.clone();
+    let rule_full_exe = full_exe.clone();
+    let core_name = format!("core.{}.{}", opts.pid, exe);
+
+    // The same details as constants, so scripts can write `home` instead of
+    // `home()`. Functions defined in the script (and imported modules) can't see
+    // these, so the functions are still needed. `groups()` is only looked up
+    // if it's used.
+    let mut scope = Scope::new();
+    scope.push_constant("home", home.clone());
+    scope.push_constant("home_exists", home_exists);
+    scope.push_constant("username", username.clone());
+    scope.push_constant("uid", uid);
+    scope.push_constant("effective_uid", effective_uid);
+    scope.push_constant("effective_username", effective_username.clone());
+    scope.push_constant("pid", pid);
+    scope.push_constant("time", time);
+    scope.push_constant("dump_mode", dump_mode);
+    scope.push_constant("signal", signal);
+    scope.push_constant("full_exe", full_exe.clone());
+    scope.push_constant("exe", exe.clone());
+    scope.push_constant("real_exe", real_exe.clone());
+    scope.push_constant("exe_deleted", exe_deleted);
+    scope.push_constant("process_uptime_secs", process_uptime_secs);
+    scope.push_constant("kernel_filename", kernel_filename.clone());
+    scope.push_constant("comm", comm_value.clone());
+    scope.push_constant("script_name", script_name.clone());
+    scope.push_constant("cgroup", cgroup.clone());
+    scope.push_constant("container_id", container_id.clone());
+    scope.push_constant("boot_id", kernel::boot_id().unwrap_or_default());
+
+    // Functions to get various details.
+    engine.register_fn("home", move || home.clone());
+    engine.register_fn("home_exists", move || home_exists);
+    engine.register_fn("username", move || username.clone());
+    engine.register_fn("groups", move || -> rhai::Array {
+        get_group_names(&groups_username, gid).into_iter().map(Into::into).collect()
+    });
+    engine.register_fn("uid", move || uid);
+    engine.register_fn("effective_uid", move || effective_uid);
+    engine.register_fn("effective_username", move || effective_username.clone());
+    engine.register_fn("pid", move || pid);
+    engine.register_fn("time", move || time);
+    engine.register_fn("capture_time_ms", || capture_time_ms() as i64);
+    engine.register_fn("dump_mode", move || dump_mode);
+    engine.register_fn("signal", move || signal);
+    engine.register_fn("full_exe", move || full_exe.clone());
+    engine.register_fn("exe", move || exe.clone());
+    engine.register_fn("real_exe", move || real_exe.clone());
+    engine.register_fn("exe_deleted", move || exe_deleted);
+    engine.register_fn("process_uptime_secs", move || process_uptime_secs);
+    engine.register_fn("kernel_filename", move || kernel_filename.clone());
+    engine.register_fn("comm", move || comm_value.clone());
+    engine.register_fn("script_name", move || script_name.clone());
+    engine.register_fn("cgroup", move || cgroup.clone());
+    engine.register_fn("container_id", move || container_id.clone());
+    engine.register_fn("boot_id", || kernel::boot_id().unwrap_or_default());
+
+    engine.register_fn("same_filesystem", |a: &str, b: &str| same_filesystem(Path::new(a), Path::new(b)));
+    engine.register_fn("mount_point", |path: &str| mounts::mount_point(Path::new(path)).to_string_lossy().into_owned());
+
+    let context = metadata::wrapper_context(std::env::vars_os());
+    engine.register_fn("ctx", move |name: &str| context.get(&name.to_lowercase()).cloned().unwrap_or_default());
+
+    let lookup = RefCell::new(lookup::Lookup::default());
+    engine.register_fn("lookup", move |file: &str, key: &str| {
+        let value = lookup
+            .borrow_mut()
+            .get(Path::new(file), key, |e| warn(&format!("lookup(): error reading {}: {:#}", file, e)));
+        trace(&format!("lookup({:?}, {:?}) = {:?}", file, key, value));
+        value
+    });
+
+    // Config-setting functions.
+    let config = SharedConfig::default();
+    // Set sensible default.
+    config.borrow_mut().permissions = 0o400u64;
+
+    let cfg = config.clone();
+    engine.register_fn("set_output_path", move |x: String| {
+        trace(&format!("set_output_path({:?})", x));
+        let mut cfg = cfg.borrow_mut();
+        cfg.output_path = x;
+        cfg.output_chosen = true;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_output_dir", move |x: &str| {
+        trace(&format!("set_output_dir({:?})", x));
+        let mut cfg = cfg.borrow_mut();
+        cfg.output_path = Path::new(x).join(&core_name).to_string_lossy().to_string();
+        cfg.output_chosen = true;
+    });
+    let cfg = config.clone();
+    engine.register_fn("skip", move || {
+        trace("skip()");
+        let mut cfg = cfg.borrow_mut();
+        cfg.output_path.clear();
+        cfg.extra_outputs.clear();
+        cfg.output_chosen = true;
+    });
+    let cfg = config.clone();
+    engine.register_fn("add_output", move |path: &str| {
+        trace(&format!("add_output({:?})", path));
+        cfg.borrow_mut().extra_outputs.push(ExtraOutput { path: PathBuf::from(path), permissions: None });
+    });
+    let cfg = config.clone();
+    engine.register_fn("add_output", move |path: &str, permissions: i64| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("add_output({:?}, {:#o})", path, permissions));
+        let permissions = permissions::check_mode("add_output", permissions)?;
+        cfg.borrow_mut().extra_outputs.push(ExtraOutput {
+            path: PathBuf::from(path),
+            permissions: Some(permissions),
+        });
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_permissions", move |x: i64| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_permissions({:#o})", x));
+        // Check here so the error points at the call in the script.
+        cfg.borrow_mut().permissions = permissions::check_mode("set_permissions", x)?;
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_permissions_str", move |x: &str| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_permissions_str({:?})", x));
+        let mode = permissions::parse_mode(x)?;
+        cfg.borrow_mut().permissions = permissions::check_mode("set_permissions_str", mode as i64)?;
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_ensure_owner_read", move |x: bool| {
+        trace(&format!("set_ensure_owner_read({:?})", x));
+        cfg.borrow_mut().ensure_owner_read = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn(
+        "set_permissions_for_exe",
+        move |pattern: &str, x: i64| -> Result<(), Box<EvalAltResult>> {
+            trace(&format!("set_permissions_for_exe({:?}, {:#o})", pattern, x));
+            let permissions = permissions::check_mode("set_permissions_for_exe", x)?;
+            // Patterns with a `/` are for the full path.
+            let name = if pattern.contains('/') { &rule_full_exe } else { &rule_exe };
+            let mut config = cfg.borrow_mut();
+            if config.exe_permissions.is_none() && glob::matches(pattern, name) {
+                config.exe_permissions = Some(permissions);
+            }
+            Ok(())
+        },
+    );
+    let cfg = config.clone();
+    engine.register_fn("set_max_size", move |x: i64| {
+        trace(&format!("set_max_size({:?})", x));
+        cfg.borrow_mut().max_size = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn(
+        "set_max_size",
+        move |x: i64, mode: &str| -> Result<(), Box<EvalAltResult>> {
+            trace(&format!("set_max_size({:?}, {:?})", x, mode));
+            let mode = match mode {
+                "delete" => MaxSizeMode::Delete,
+                "truncate" => MaxSizeMode::Truncate,
+                _ => return Err(format!("invalid max size mode '{}'; expected 'delete' or 'truncate'", mode).into()),
+            };
+            let mut cfg = cfg.borrow_mut();
+            cfg.max_size = Some(x.max(0) as u64);
+            cfg.max_size_mode = mode;
+            Ok(())
+        },
+    );
+
+    let cfg = config.clone();
+    engine.register_fn("set_save_proc", move |x: bool| {
+        trace(&format!("set_save_proc({:?})", x));
+        cfg.borrow_mut().save_proc = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_save_metadata", move |x: bool| {
+        trace(&format!("set_save_metadata({:?})", x));
+        cfg.borrow_mut().save_metadata = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_max_age_days", move |x: i64| {
+        trace(&format!("set_max_age_days({:?})", x));
+        cfg.borrow_mut().retention.max_age_days = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_max_total_bytes", move |x: i64| {
+        trace(&format!("set_max_total_bytes({:?})", x));
+        cfg.borrow_mut().retention.max_total_bytes = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_max_per_exe", move |x: i64| {
+        trace(&format!("set_max_per_exe({:?})", x));
+        cfg.borrow_mut().retention.max_per_exe = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_user_quota_bytes", move |x: i64| {
+        trace(&format!("set_user_quota_bytes({:?})", x));
+        cfg.borrow_mut().user_quota_bytes = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_retention_dir", move |x: &str| {
+        trace(&format!("set_retention_dir({:?})", x));
+        cfg.borrow_mut().retention_dir = x.to_owned();
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_use_crash_time", move |x: bool| {
+        trace(&format!("set_use_crash_time({:?})", x));
+        cfg.borrow_mut().use_crash_time = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_keep_empty", move |x: bool| {
+        trace(&format!("set_keep_empty({:?})", x));
+        cfg.borrow_mut().keep_empty = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_latest_symlink", move |x: bool| {
+        trace(&format!("set_latest_symlink({:?})", x));
+        cfg.borrow_mut().latest_symlink = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_min_free_inodes", move |x: i64| {
+        trace(&format!("set_min_free_inodes({:?})", x));
+        cfg.borrow_mut().min_free_inodes = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_tag", move |key: &str, value: &str| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_tag({:?}, {:?})", key, value));
+        metadata::check_tag_key(key)?;
+        cfg.borrow_mut().tags.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_post_hook", move |command: &str| {
+        trace(&format!("set_post_hook({:?})", command));
+        cfg.borrow_mut().post_hook = Some(command.to_owned()).filter(|command| !command.is_empty());
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_post_hook_timeout_secs", move |x: i64| {
+        trace(&format!("set_post_hook_timeout_secs({:?})", x));
+        cfg.borrow_mut().post_hook_timeout_secs = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_capture_env", move |x: bool| {
+        trace(&format!("set_capture_env({:?})", x));
+        cfg.borrow_mut().capture_env = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_capture_cmdline", move |x: bool| {
+        trace(&format!("set_capture_cmdline({:?})", x));
+        cfg.borrow_mut().capture_cmdline = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_save_fds", move |x: bool| {
+        trace(&format!("set_save_fds({:?})", x));
+        cfg.borrow_mut().save_fds = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_redact", move |pattern: &str| {
+        trace(&format!("set_redact({:?})", pattern));
+        cfg.borrow_mut().redact.push(pattern.to_owned());
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_force_dump", move |x: bool| {
+        trace(&format!("set_force_dump({:?})", x));
+        cfg.borrow_mut().force_dump = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_layout", move |x: &str| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_layout({:?})", x));
+        cfg.borrow_mut().layout = x.parse::<layout::Layout>()?;
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_boot_dir", move |x: bool| {
+        trace(&format!("set_boot_dir({:?})", x));
+        cfg.borrow_mut().boot_dir = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_shard", move |x: bool| {
+        trace(&format!("set_shard({:?})", x));
+        cfg.borrow_mut().shard = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_privileged_write", move |x: bool| {
+        trace(&format!("set_privileged_write({:?})", x));
+        cfg.borrow_mut().privileged_write = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_capture_signals", move |signals: rhai::Array| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_capture_signals({:?})", signals));
+        let signals = signals
+            .into_iter()
+            .map(|signal| match signal.as_int() {
+                Ok(signal @ 1..=64) => Ok(signal as u32),
+                _ => Err(format!("invalid signal {}; expected a number from 1 to 64", signal)),
+            })
+            .collect::<Result<_, _>>()?;
+        cfg.borrow_mut().capture_signals = Some(signals);
+        Ok(())
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_keep_first", move |x: i64| {
+        trace(&format!("set_keep_first({:?})", x));
+        cfg.borrow_mut().keep_first = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_sample_rate", move |x: i64| {
+        trace(&format!("set_sample_rate({:?})", x));
+        cfg.borrow_mut().sample_rate = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_storm_threshold", move |x: i64| {
+        trace(&format!("set_storm_threshold({:?})", x));
+        cfg.borrow_mut().storm_threshold = Some(x as u64).filter(|_| x > 0);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_storm_auto_sample", move |x: bool| {
+        trace(&format!("set_storm_auto_sample({:?})", x));
+        cfg.borrow_mut().storm_auto_sample = x;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_debounce_secs", move |x: i64| {
+        trace(&format!("set_debounce_secs({:?})", x));
+        cfg.borrow_mut().debounce_secs = Some(x.max(0) as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_split_bytes", move |x: i64| {
+        trace(&format!("set_split_bytes({:?})", x));
+        cfg.borrow_mut().split_bytes = (x > 0).then_some(x as u64);
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_save_header", move |x: i64| {
+        trace(&format!("set_save_header({:?})", x));
+        cfg.borrow_mut().save_header = x.clamp(0, MAX_HEADER_BYTES as i64) as u64;
+    });
+    let cfg = config.clone();
+    engine.register_fn("set_umask", move |x: i64| -> Result<(), Box<EvalAltResult>> {
+        trace(&format!("set_umask({:#o})", x));
+        cfg.borrow_mut().umask = Some(permissions::check_umask(x)?);
+        Ok(())
+    });
+    (engine, scope, config)
+}
+
+/// `path` moved into a directory called `dir` next to it.
+fn in_subdir(path: &Path, dir: &str) -> PathBuf {
+    let subdir = path.parent().unwrap_or(Path::new("")).join(dir);
+    match path.file_name() {
+        Some(name) => subdir.join(name),
+        None => subdir,
+    }
+}
+
+/// The kernel's `comm` for `exe` if we can't read it: the first 15 bytes.
+fn truncate_comm(exe: &str) -> String {
+    let mut end = exe.len().min(15);
+    while !exe.is_char_boundary(end) {
+        end -= 1;
+    }
+    exe[..end].to_owned()
+}
+
+/// Generated routing tables can be big, but not this big.
+const MAX_CONFIG_BYTES: usize = 256 * 1024 * 1024;
+
+fn read_gzip_config(path: &Path) -> Result<String> {
+    let error = |e: anyhow::Error| ConfigError(format!("error reading {}: {:#}", path.display(), e));
+    let compressed = fs::read(path).map_err(|e| error(e.into()))?;
+    let script = gzip::decompress(&compressed, MAX_CONFIG_BYTES).map_err(error)?;
+    String::from_utf8(script).map_err(|e| error(e.into())).map_err(Into::into)
+}
+
+/// A core that `write_output()` kept.
+struct WrittenCore {
+    path: PathBuf,
+    /// Number of bytes of core written.
+    bytes: u64,
+    mode: libc::mode_t,
+    /// Cut short by `core_limit` or `set_max_size()`.
+    truncated: bool,
+    /// The first `set_save_header()` bytes.
+    header: Vec<u8>,
+    /// From the core's `NT_SIGINFO` note, for the sidecar.
+    siginfo: Option<elf::Siginfo>,
+    /// From the core's ELF header, for the sidecar.
+    arch: Option<String>,
+    /// With `set_split_bytes()` the core is in this many `<path>.NNN` files
+    /// rather than at `path`.
+    chunks: Option<u32>,
+}
+
+impl WrittenCore {
+    /// The files the core itself is in.
+    fn files(&self) -> Vec<PathBuf> {
+        match self.chunks {
+            Some(chunks) => (0..chunks).map(|i| chunk_path(&self.path, i)).collect(),
+            None => vec![self.path.clone()],
+        }
+    }
+}
+
+/// Chunk `i` of a core split with `set_split_bytes()`.
+fn chunk_path(path: &Path, i: u32) -> PathBuf {
+    with_suffix(path, &format!(".{:03}", i))
+}
+
+/// An output that's being written.
+struct OutputFile {
+    path: PathBuf,
+    mode: libc::mode_t,
+    /// `set_split_bytes()`, if the core is being split into chunks.
+    split: Option<u64>,
+    /// Number of chunks opened so far.
+    chunks: u32,
+    /// Bytes written to the current file.
+    file_bytes: u64,
+    writer: BufWriter<fs::File>,
+}
+
+impl OutputFile {
+    /// Write to the current file, moving on to the next chunk when it's full.
+    fn write(&mut self, mut data: &[u8], crash_time: Option<u32>) -> Result<()> {
+        while !data.is_empty() {
+            if self.split == Some(self.file_bytes) {
+                let next = chunk_path(&self.path, self.chunks);
+                let file = create_output_file(&next, self.mode)?;
+                PARTIAL_FILES.lock().unwrap_or_else(|e| e.into_inner()).push(next);
+                let full = std::mem::replace(&mut self.writer, BufWriter::new(file));
+                finish_file(full, crash_time)?;
+                self.chunks += 1;
+                self.file_bytes = 0;
+            }
+            let n = match self.split {
+                Some(split) => data.len().min((split - self.file_bytes) as usize),
+                None => data.len(),
+            };
+            self.writer.write_all(&data[..n]).context("error writing core dump")?;
+            self.file_bytes += n as u64;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+}
+
+/// Flush a finished core (or chunk) and set its times to `crash_time`.
+fn finish_file(writer: BufWriter<fs::File>, crash_time: Option<u32>) -> Result<()> {
+    let out = writer.into_inner().map_err(|e| e.into_error()).context("error writing core dump")?;
+    // The write can take a while for big cores so this has to be afterwards.
+    if let Some(time) = crash_time {
+        set_fd_times(&out, time).context("error setting core dump times")?;
+    }
+    Ok(())
+}
+
+/// The header is kept in memory so don't let it get silly.
+const MAX_HEADER_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How much of the core to keep to look for `NT_SIGINFO` for the sidecar. The
+/// notes come straight after the program headers, which are only big if the
+/// process has a lot of mappings.
+const SIGINFO_BYTES: u64 = 1024 * 1024;
+
+/// Cores that are still being written, for the `--deadline` watchdog to
+/// clean up.
+static PARTIAL_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+const COPY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Copy `input` to all the configured outputs in one pass. If one fails (e.g.
+/// its disk is full) the rest carry on, and it's only an error if they all
+/// fail. Returns the cores that were kept.
+fn write_output(config: &Config, opts: &Opts, input: &mut impl Read) -> Result<Vec<WrittenCore>> {
+    write_output_buffered(config, opts, input, COPY_BUFFER_BYTES)
+}
+
+/// `write_output()` with a different buffer size, for `sellafield bench`.
+fn write_output_buffered(
+    config: &Config,
+    opts: &Opts,
+    input: &mut impl Read,
+    buffer_bytes: usize,
+) -> Result<Vec<WrittenCore>> {
+    let result = copy_to_outputs(config, opts, input, buffer_bytes);
+    // Whatever happened they aren't partial any more.
+    PARTIAL_FILES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    result
+}
+
+fn copy_to_outputs(
+    config: &Config,
+    opts: &Opts,
+    input: &mut impl Read,
+    buffer_bytes: usize,
+) -> Result<Vec<WrittenCore>> {
+    let core_limit = opts.core_limit;
+
+    // Set the umask otherwise it creates directories that are world-writable.
+    // The script can override this but it's only really relevant for
+    // directories; the core file itself always gets an explicit fchmod().
+    let umask = config.umask.unwrap_or(0o022);
+    set_umask(umask as libc::mode_t);
+
+    let mut first_error = None;
+    let mut failed = |path: &Path, e: anyhow::Error| {
+        warn(&format!("error writing core to {}: {:#}", path.display(), e));
+        if is_read_only(&e) {
+            syslog(&format!(
+                "can't write core to {} because the filesystem is read-only; if it was remounted read-only \
+                 there may be disk errors (check dmesg)",
+                path.display()
+            ));
+        }
+        first_error.get_or_insert(e);
+    };
+
+    let split = config.split_bytes.filter(|&bytes| bytes > 0);
+    let crash_time = config.use_crash_time.then_some(opts.time);
+
+    let mut files = Vec::new();
+    for output in config.outputs() {
+        if !enough_free_inodes(config, &output.path)? {
+            continue;
+        }
+        let first = match split {
+            Some(_) => chunk_path(&output.path, 0),
+            None => output.path.clone(),
+        };
+        match open_output(&first, output.permissions) {
+            Ok((file, mode)) => {
+                PARTIAL_FILES.lock().unwrap_or_else(|e| e.into_inner()).push(first);
+                files.push(OutputFile {
+                    path: output.path,
+                    mode,
+                    split,
+                    chunks: 1,
+                    file_bytes: 0,
+                    writer: BufWriter::new(file),
+                })
+            }
+            Err(e) => failed(&output.path, e),
+        }
+    }
+
+    // If there's a max size we read up to it and then check whether there's
+    // anything left. That way we never write more than `max_size` bytes.
+    let read_limit = match config.max_size {
+        Some(max_size) => core_limit.min(max_size),
+        None => core_limit,
+    };
+
+    let mut limited = input.by_ref().take(read_limit);
+    let mut buffer = vec![0u8; buffer_bytes.max(1)];
+    let mut written = 0;
+    // The start of the core, for `set_save_header()` and the sidecar.
+    let prefix_bytes = match config.save_metadata {
+        true => config.save_header.max(SIGINFO_BYTES),
+        false => config.save_header,
+    };
+    let mut prefix = Vec::new();
+    let mut progress = opts.progress.then(|| Progress::new(opts.pid));
+    let mut heartbeat = Heartbeat::new(opts);
+    while !files.is_empty() {
+        let n = match limited.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("error reading core dump"),
+        };
+        files.retain_mut(|file| match file.write(&buffer[..n], crash_time) {
+            Ok(()) => true,
+            Err(e) => {
+                failed(&file.path, e);
+                false
+            }
+        });
+        if (prefix.len() as u64) < prefix_bytes {
+            let wanted = (prefix_bytes - prefix.len() as u64).min(n as u64) as usize;
+            prefix.extend_from_slice(&buffer[..wanted]);
+        }
+        written += n as u64;
+        if let Some(progress) = &mut progress {
+            progress.update(written);
+        }
+        if let Some(heartbeat) = &mut heartbeat {
+            heartbeat.beat(|| format!("writing core, {} MB so far", written / 1_000_000));
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.finish(written);
+    }
+
+    let header = &prefix[..prefix.len().min(config.save_header as usize)];
+    let siginfo = if config.save_metadata { elf::siginfo(&prefix) } else { None };
+    let arch = if config.save_metadata { elf::arch(&prefix) } else { None };
+    let mut kept = Vec::new();
+    for file in files {
+        if let Err(e) = finish_file(file.writer, crash_time) {
+            failed(&file.path, e);
+            continue;
+        }
+        kept.push(WrittenCore {
+            path: file.path,
+            bytes: written,
+            mode: file.mode,
+            truncated: false,
+            header: header.to_vec(),
+            siginfo: siginfo.clone(),
+            arch: arch.clone(),
+            chunks: file.split.map(|_| file.chunks),
+        });
+    }
+
+    if kept.is_empty() {
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        return Ok(kept);
+    }
+
+    // The kernel sometimes gives us nothing, and an empty file just looks
+    // like something went wrong.
+    if written == 0 && !config.keep_empty {
+        syslog(&format!("core from pid {} was empty; not keeping it", opts.pid));
+        for core in kept {
+            for file in core.files() {
+                fs::remove_file(file)?;
+            }
+        }
+        return Ok(Vec::new());
+    }
+
+    // See if there was more than we read.
+    let mut truncated = false;
+    if written == read_limit {
+        let mut probe = [0u8; 1];
+        truncated = input.read(&mut probe).context("error reading core dump")? != 0;
+    }
+    for core in &mut kept {
+        core.truncated = truncated;
+    }
+
+    // Cut short by set_max_size() rather than the core limit.
+    if truncated && read_limit < core_limit {
+        match config.max_size_mode {
+            MaxSizeMode::Delete => {
+                for core in kept {
+                    for file in core.files() {
+                        fs::remove_file(file)?;
+                    }
+                }
+                return Ok(Vec::new());
+            }
+            MaxSizeMode::Truncate => {
+                for core in &mut kept {
+                    let unmarked = core.files();
+                    core.path = with_suffix(&core.path, ".truncated");
+                    for (from, to) in unmarked.iter().zip(core.files()) {
+                        fs::rename(from, to)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Create the output file, and its directory if necessary.
+fn open_output(path: &Path, permissions: u64) -> Result<(fs::File, libc::mode_t)> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mode = permissions_mode(permissions)?;
+    Ok((create_output_file(path, mode)?, mode))
+}
+
+/// Check `set_min_free_inodes()` for the filesystem `path` would be written
+/// to, logging if there aren't enough.
+fn enough_free_inodes(config: &Config, path: &Path) -> Result<bool> {
+    let min_free_inodes = match config.min_free_inodes {
+        Some(min_free_inodes) => min_free_inodes,
+        None => return Ok(true),
+    };
+    let dir = existing_ancestor(path);
+    let free = match free_inodes(&dir)? {
+        Some(free) => free,
+        None => return Ok(true),
+    };
+    trace(&format!("{} free inodes on {}", free, dir.display()));
+    if free < min_free_inodes {
+        warn(&format!(
+            "only {} free inodes on {} (minimum {}); not writing {}",
+            free,
+            dir.display(),
+            min_free_inodes,
+            path.display()
+        ));
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn permissions_mode(permissions: u64) -> Result<libc::mode_t> {
+    let permissions_mode: u16 = permissions.try_into().map_err(|e| {
+        anyhow!(
+            "Sellafield config script returned invalid permissions mode: {}. {}",
+            permissions,
+            e
+        )
+    })?;
+    Ok(permissions_mode as libc::mode_t)
+}
+
+/// Create a new file at `path` with exactly `mode` permissions, replacing
+/// anything that's already there.
+fn create_output_file(path: &Path, mode: libc::mode_t) -> Result<fs::File> {
+    // Replace any existing file rather than truncating it. This means we
+    // never write through a symlink someone has left at the output path
+    // (removing a symlink removes the link, not its target).
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    // Create the file owner-only so nobody can open it before we've set the
+    // real permissions below. O_EXCL + O_NOFOLLOW means if anything has
+    // reappeared at the path since we removed it we fail rather than use it.
+    let file = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    // Set the requested permissions on the file we actually opened, rather
+    // than going back through the path. This is independent of the umask.
+    set_fd_permissions(&file, mode).context("error setting core dump permissions")?;
+
+    Ok(file)
+}
+
+/// Write the `/proc/<pid>` snapshots next to the core as `<core>.status` and
+/// `<core>.stat`, with the same permissions as the core.
+fn write_proc_files(core: &WrittenCore, process: &ProcessInfo) -> Result<()> {
+    for (suffix, contents) in [(".status", &process.status), (".stat", &process.stat)] {
+        if let Some(contents) = contents {
+            let mut file = create_output_file(&with_suffix(&core.path, suffix), core.mode)?;
+            file.write_all(contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the first `set_save_header()` bytes of the core as `<core>.head`,
+/// with the same permissions as the core.
+fn write_header(core: &WrittenCore) -> Result<()> {
+    let mut file = create_output_file(&with_suffix(&core.path, ".head"), core.mode)?;
+    file.write_all(&core.header)?;
+    Ok(())
+}
+
+/// Point `<dir>/<exe>.latest` at `core_path`, which is in `<dir>`.
+fn update_latest_symlink(core_path: &Path, exe: &str, pid: u32) -> Result<()> {
+    let (dir, target) = match (core_path.parent(), core_path.file_name()) {
+        (Some(dir), Some(target)) => (dir, target),
+        _ => return Ok(()),
+    };
+    let link = dir.join(format!("{}.latest", exe));
+    // Make the new link under a temporary name and rename it over the old
+    // one so it's atomic. rename() replaces the link itself, so it doesn't
+    // matter if someone has pointed the old one somewhere else.
+    let temp = dir.join(format!(".{}.latest.{}", exe, pid));
+    match fs::remove_file(&temp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    fs_err::os::unix::fs::symlink(target, &temp)?;
+    if let Err(e) = fs::rename(&temp, &link) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Write the JSON sidecar as `<core>.json`, with the same permissions as the core.
+fn write_metadata(core: &WrittenCore, metadata: &Metadata) -> Result<()> {
+    let file = create_output_file(&with_suffix(&core.path, ".json"), core.mode)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, metadata).context("error writing metadata")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Append `suffix` to the file name, e.g. `core.123` -> `core.123.stat`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[derive(Clone)]
+struct UserDetails {
+    username: String,
+    home: PathBuf,
+    /// Primary group ID.
+    gid: u32,
+}
+
+#[cfg(unix)]
+fn get_user_details(uid: u32) -> Result<UserDetails> {
+    // getpwuid() returns a static buffer, so use the reentrant version. The
+    // strings go in `buffer`, which is grown if it's too small.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let rc = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match rc {
+            0 => break,
+            libc::ERANGE if buffer.len() < 1024 * 1024 => buffer.resize(buffer.len() * 2, 0),
+            libc::EINTR => {}
+            _ => bail!("Error getting username for user ID {}: {}", uid, Errno(rc)),
+        }
+    }
+    if result.is_null() {
+        bail!("User ID {} not found in the user database", uid);
+    }
+
+    let pw_name_cstr: &CStr = unsafe { CStr::from_ptr(passwd.pw_name) };
+    let pw_name = latin1_to_string(pw_name_cstr.to_bytes());
+
+    let pw_dir_cstr: &CStr = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    let pw_dir = latin1_to_path(pw_dir_cstr.to_bytes());
+
+    let pw_gid = passwd.pw_gid;
+
+    Ok(UserDetails {
+        username: pw_name,
+        home: pw_dir,
+        gid: pw_gid,
+    })
+}
+
+/// Names of all the groups `username` is in, including the primary group
+/// `gid`. Returns an empty list if they can't be found.
+#[cfg(unix)]
+fn get_group_names(username: &str, gid: u32) -> Vec<String> {
+    let c_username = match CString::new(username) {
+        Ok(c_username) => c_username,
+        Err(_) => return Vec::new(),
+    };
+
+    // getgrouplist() tells us how big the buffer needs to be if it's too
+    // small, but try again with a bigger one in case it changes in between.
+    let mut gids: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut ngroups = gids.len() as libc::c_int;
+        let rc = unsafe { libc::getgrouplist(c_username.as_ptr(), gid, gids.as_mut_ptr(), &mut ngroups) };
+        if rc >= 0 {
+            gids.truncate(ngroups.max(0) as usize);
+            break;
+        }
+        let needed = (ngroups.max(0) as usize).max(gids.len() * 2);
+        if needed > 65536 {
+            return Vec::new();
+        }
+        gids.resize(needed, 0);
+    }
+
+    gids.into_iter()
+        .filter_map(|gid| {
+            let group = unsafe { libc::getgrgid(gid) };
+            if group.is_null() {
+                return None;
+            }
+            let name: &CStr = unsafe { CStr::from_ptr((*group).gr_name) };
+            Some(latin1_to_string(name.to_bytes()))
+        })
+        .collect()
+}
+
+/// The uid to write cores as, and the user whose groups to use: the crashing
+/// user, unless `--output-uid` or `--output-gid` say otherwise.
+fn output_owner(opts: &Opts, user: &UserDetails) -> Result<(u32, UserDetails)> {
+    let (uid, mut owner) = match opts.output_uid {
+        Some(uid) => (uid, get_user_details(uid).context("invalid --output-uid")?),
+        None => (opts.uid, user.clone()),
+    };
+    if let Some(gid) = opts.output_gid {
+        if unsafe { libc::getgrgid(gid) }.is_null() {
+            bail!("invalid --output-gid: group ID {} not found in the group database", gid);
+        }
+        owner.gid = gid;
+    }
+    Ok((uid, owner))
+}
+
+/// Switch to the user's uid and groups. With `keep_saved` (and if we're root)
+/// root stays the saved uid, so `set_effective_uid()` can get it back; call
+/// `drop_saved_privileges()` to give it up for good.
+#[cfg(unix)]
+fn drop_privileges(uid: u32, user: &UserDetails, keep_saved: bool) -> Result<()> {
+    // The groups have to go first because we can't change them afterwards.
+    // Otherwise directories we create would belong to root's group, and the
+    // user's other groups wouldn't apply.
+    if unsafe { libc::geteuid() } == 0 {
+        let username = CString::new(user.username.as_bytes())?;
+        set_errno(Errno(0));
+        if unsafe { libc::initgroups(username.as_ptr(), user.gid) } != 0 {
+            bail!("Error setting groups for {}: {}", user.username, errno());
+        }
+        set_errno(Errno(0));
+        if unsafe { libc::setgid(user.gid) } != 0 {
+            bail!("Error switching to group ID {}: {}", user.gid, errno());
+        }
+    }
+
+    // This is not strictly necessary in this case, but you can't be too
+    // safe when dealing with C.
+    let saved = if keep_saved && unsafe { libc::geteuid() } == 0 { 0 } else { uid };
+    set_errno(Errno(0));
+    let rc = unsafe { libc::setresuid(uid, uid, saved) };
+    if rc != 0 {
+        bail!("Error switching to user ID {}: {}", uid, errno());
+    }
+    Ok(())
+}
+
+/// Give up root entirely after `drop_privileges()` kept it as the saved uid.
+#[cfg(unix)]
+fn drop_saved_privileges(uid: u32) -> Result<()> {
+    set_errno(Errno(0));
+    if unsafe { libc::setresuid(uid, uid, uid) } != 0 {
+        bail!("Error giving up root for user ID {}: {}", uid, errno());
+    }
+    Ok(())
+}
+
+/// Switch the effective uid to root or back to the user, for
+/// `set_privileged_write()`.
+#[cfg(unix)]
+fn set_effective_uid(uid: u32) -> Result<()> {
+    set_errno(Errno(0));
+    if unsafe { libc::seteuid(uid) } != 0 {
+        bail!("Error switching effective user ID to {}: {}", uid, errno());
+    }
+    Ok(())
+}
+
+/// Chown a core written as root, and its sidecars, to the user.
+fn give_to_user(core: &WrittenCore, uid: u32, gid: u32) {
+    let mut files = core.files();
+    files.extend(
+        retention::SIDECAR_SUFFIXES
+            .iter()
+            .map(|suffix| with_suffix(&core.path, suffix))
+            .filter(|path| path.exists()),
+    );
+    for file in files {
+        if let Err(e) = std::os::unix::fs::lchown(&file, Some(uid), Some(gid)) {
+            warn(&format!("error giving {} to user ID {}: {}", file.display(), uid, e));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_umask(mask: libc::mode_t) {
+    // This always succeeds.
+    unsafe {
+        libc::umask(mask);
+    }
+}
+
+fn latin1_to_string(s: &[u8]) -> String {
+    s.iter().map(|&c| c as char).collect()
+}
+
+fn latin1_to_path(s: &[u8]) -> PathBuf {
+    let os_str = OsStr::from_bytes(s);
+    PathBuf::from(os_str)
+}
+
+/// The closest ancestor of `path` that exists, so we can check the
+/// filesystem before creating any directories.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("/"))
+        .to_owned()
+}
+
+/// Whether `a` and `b` are on the same filesystem, e.g. so they can be hard
+/// linked or renamed between. False if either can't be stat'd. Links and
+/// renames don't work between two bind mounts of the same filesystem either,
+/// so they have to be under the same mount point too.
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a_meta), Ok(b_meta)) => a_meta.dev() == b_meta.dev() && mounts::mount_point(a) == mounts::mount_point(b),
+        _ => false,
+    }
+}
+
+/// Number of inodes available to unprivileged users on the filesystem
+/// containing `path`, or `None` if it doesn't have a fixed number (e.g.
+/// btrfs reports 0 total).
+#[cfg(unix)]
+fn free_inodes(path: &Path) -> Result<Option<u64>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    set_errno(Errno(0));
+    let rc = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if rc != 0 {
+        bail!("Error getting filesystem stats for {}: {}", path.display(), errno());
+    }
+    if stat.f_files == 0 {
+        return Ok(None);
+    }
+    Ok(Some(stat.f_favail as u64))
+}
+
+/// Set the access and modification times to `time` (Unix seconds).
+#[cfg(unix)]
+fn set_fd_times(file: &fs::File, time: u32) -> Result<()> {
+    let timespec = libc::timespec {
+        tv_sec: time as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [timespec, timespec];
+    set_errno(Errno(0));
+    let rc = unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) };
+    if rc != 0 {
+        bail!("Error setting times on {}: {}", file.path().display(), errno());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_fd_permissions(file: &fs::File, mode: libc::mode_t) -> Result<()> {
+    set_errno(Errno(0));
+    let rc = unsafe { libc::fchmod(file.as_raw_fd(), mode) };
+    if rc != 0 {
+        bail!("Error setting mode {:o} on {}: {}", mode, file.path().display(), errno());
+    }
+    Ok(())
 }
 
 
 /// Extract the base cmd from a path
-fn cmd<'a>(default: &'a String, path: &'a String) -> &'a str {
-    std::path::Path::new(path).file_name().map(|s| s.to_str()).flatten().unwrap_or(default.as_str())
+fn cmd<'a>(default: &'a str, path: &'a str) -> &'a str {
+    std::path::Path::new(path).file_name().and_then(|s| s.to_str()).unwrap_or(default)
 }
 
 /// Fallible version of argh::from_env().
 pub fn try_from_env<T: argh::TopLevelCommand>() -> std::result::Result<T, argh::EarlyExit> {
     let strings: Vec<String> = std::env::args().collect();
+    try_from_args(&strings)
+}
+
+fn try_from_args<T: argh::TopLevelCommand>(strings: &[String]) -> std::result::Result<T, argh::EarlyExit> {
     let cmd = cmd(&strings[0], &strings[0]);
     let strs: Vec<&str> = strings.iter().map(|s| s.as_str()).collect();
     T::from_args(&[cmd], &strs[1..])
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        capture_time_ms, chunk_path, counter, decode_exe, drop_privileges, error_exit_code, exe_name, free_inodes,
+        get_group_names, get_user_details, handle_crash, home_fallback_path, is_read_only, mb_per_sec,
+        metadata::Metadata, open_output, parse_signals, retention, run_script, same_filesystem, script_fallback_dir,
+        shard_name, try_from_args, unpack_args, update_latest_symlink, with_suffix, write_output, Config, ExeEncoding,
+        Opts, Outcome, ProcessInfo,
+    };
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn limit(core_limit: u64) -> Opts {
+        Opts {
+            core_limit,
+            ..Default::default()
+        }
+    }
+
+    /// The mode the core gets with `set_permissions(permissions)` and
+    /// `set_umask(umask)`. The umask is process-wide and other tests create
+    /// files that other users need to read, so only use ones that leave them
+    /// readable.
+    fn written_mode(permissions: u64, umask: u64) -> u32 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cores/core.1.test");
+        let config = Config {
+            output_path: path.to_string_lossy().to_string(),
+            permissions,
+            umask: Some(umask),
+            ..Default::default()
+        };
+        write_output(&config, &limit(100), &mut "core".as_bytes()).unwrap();
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn run_test_script(script: &str) -> anyhow::Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.rhai");
+        std::fs::write(&path, script).unwrap();
+        let uid = unsafe { libc::getuid() };
+        let opts = Opts {
+            uid,
+            pid: 42,
+            time: 1700000000,
+            exe: "!usr!bin!foo".to_owned(),
+            core_limit: 1000,
+            config: path,
+            ..Default::default()
+        };
+        run_script(&opts, "/usr/bin/foo", "foo", &get_user_details(uid).unwrap(), &ProcessInfo::default())
+    }
+
+    /// `handle_crash()` options for running `script` on a 10 byte core, with
+    /// the core, the script and the lock directory all in `dir`.
+    fn crash_opts(dir: &Path, script: &str) -> Opts {
+        let input = dir.join("core.bin");
+        std::fs::write(&input, "0123456789").unwrap();
+        let config = dir.join("config.rhai");
+        std::fs::write(&config, script).unwrap();
+        Opts {
+            uid: unsafe { libc::getuid() },
+            core_limit: 100,
+            config,
+            input: Some(input),
+            lock_dir: dir.join("lock"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_set_permissions_validation() {
+        assert_eq!(run_test_script("set_permissions(0o644);").unwrap().permissions, 0o644);
+
+        for bad in ["-1", "0o10000", "0o4600", "0o2710"] {
+            let script = format!("let x = 1;\nset_permissions({});", bad);
+            let error = run_test_script(&script).unwrap_err().to_string();
+            assert!(error.contains("invalid permissions"), "{}", error);
+            assert!(error.contains("for set_permissions()"), "{}", error);
+            assert!(error.contains("line 2"), "{}", error);
+        }
+        assert_eq!(run_test_script("set_permissions(0o1640);").unwrap().permissions, 0o1640);
+        for bad in [r#"set_permissions_str("4755");"#, r#"add_output("/tmp/core", 0o6644);"#] {
+            assert!(run_test_script(bad).unwrap_err().to_string().contains("setuid and setgid"), "{}", bad);
+        }
+
+        assert_eq!(run_test_script("set_umask(0o077);").unwrap().umask, Some(0o077));
+        for bad in ["-1", "0o1000"] {
+            let error = run_test_script(&format!("set_umask({});", bad)).unwrap_err().to_string();
+            assert!(error.contains("invalid umask"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_scope_variables() {
+        let uid = unsafe { libc::getuid() };
+        let config = run_test_script(&format!(
+            r#"if uid == {} && uid() == uid && pid == 42 {{ set_output_path(home + "/cores/" + exe); }}"#,
+            uid
+        ))
+        .unwrap();
+        let home = get_user_details(uid).unwrap().home;
+        assert_eq!(config.output_path, format!("{}/cores/foo", home.display()));
+
+        // They're constants.
+        assert!(run_test_script("exe = \"bar\";").is_err());
+    }
+
+    #[test]
+    fn test_set_permissions_str() {
+        assert_eq!(run_test_script(r#"set_permissions_str("rw-r-----");"#).unwrap().permissions, 0o640);
+        let error = run_test_script(r#"set_permissions_str("rw-r----Q");"#).unwrap_err().to_string();
+        assert!(error.contains("character 9"), "{}", error);
+    }
+
+    #[test]
+    fn test_set_permissions_for_exe() {
+        let permissions = |script: &str| {
+            let mut config = run_test_script(script).unwrap();
+            config.output_path = "/tmp/core".to_owned();
+            config.outputs()[0].permissions
+        };
+        assert_eq!(permissions(r#"set_permissions_for_exe("bar*", 0o400); set_permissions(0o640);"#), 0o640);
+        assert_eq!(permissions(r#"set_permissions_for_exe("f?o", 0o400); set_permissions(0o640);"#), 0o400);
+        assert_eq!(permissions(r#"set_permissions_for_exe("/usr/*", 0o440);"#), 0o440);
+        // Only whole paths match when there's a `/`.
+        assert_eq!(permissions(r#"set_permissions_for_exe("/foo", 0o440); set_permissions(0o600);"#), 0o600);
+        // First wins.
+        assert_eq!(
+            permissions(r#"set_permissions_for_exe("foo", 0o400); set_permissions_for_exe("*", 0o644);"#),
+            0o400
+        );
+
+        let error = run_test_script(r#"set_permissions_for_exe("bar", 0o10000);"#).unwrap_err().to_string();
+        assert!(error.contains("invalid permissions"), "{}", error);
+    }
+
+    #[test]
+    fn test_systemd_layout() {
+        let config = run_test_script(r#"set_output_path("/tmp/core"); set_layout("systemd");"#).unwrap();
+        let prefix = format!("/var/lib/systemd/coredump/core.foo.{}.", unsafe { libc::getuid() });
+        assert!(config.output_path.starts_with(&prefix), "{}", config.output_path);
+        assert!(config.output_path.ends_with(".42.1700000000000000"), "{}", config.output_path);
+
+        assert_eq!(run_test_script(r#"set_output_path("/tmp/core");"#).unwrap().output_path, "/tmp/core");
+        let error = run_test_script(r#"set_layout("apport");"#).unwrap_err().to_string();
+        assert!(error.contains("invalid layout"), "{}", error);
+    }
+
+    #[test]
+    fn test_boot_dir() {
+        let boot_id = crate::kernel::boot_id().unwrap();
+        let script = r#"set_output_path("/tmp/cores/core"); add_output("copy", 0o600); set_boot_dir(true);"#;
+        let config = run_test_script(script).unwrap();
+        assert_eq!(config.output_path, format!("/tmp/cores/boot-{}/core", boot_id));
+        assert_eq!(config.extra_outputs[0].path, Path::new(&format!("boot-{}/copy", boot_id)));
+
+        let script = r#"set_output_path("/tmp/core"); set_layout("systemd"); set_boot_dir(true);"#;
+        let config = run_test_script(script).unwrap();
+        let prefix = format!("/var/lib/systemd/coredump/boot-{}/core.foo.", boot_id);
+        assert!(config.output_path.starts_with(&prefix), "{}", config.output_path);
+
+        let config = run_test_script(r#"if boot_id() != boot_id { throw "mismatch"; } set_output_path(boot_id);"#);
+        assert_eq!(config.unwrap().output_path, boot_id);
+    }
+
+    #[test]
+    fn test_shard() {
+        let config = run_test_script(r#"set_output_dir("/var/cores"); set_shard(true);"#).unwrap();
+        // PID 42.
+        assert_eq!(config.output_path, "/var/cores/2a/core.42.foo");
+        assert_eq!(config.retention_dir(), Some(PathBuf::from("/var/cores")));
+        assert_eq!(config.output_dir(Path::new(&config.output_path)), Some(PathBuf::from("/var/cores")));
+
+        let config = run_test_script(r#"set_output_dir("/var/cores"); set_boot_dir(true); set_shard(true);"#).unwrap();
+        let boot_id = crate::kernel::boot_id().unwrap();
+        assert_eq!(config.output_path, format!("/var/cores/boot-{}/2a/core.42.foo", boot_id));
+        assert_eq!(config.retention_dir(), Some(PathBuf::from("/var/cores")));
+
+        assert_eq!(run_test_script(r#"set_output_dir("/var/cores");"#).unwrap().output_path, "/var/cores/core.42.foo");
+        assert_eq!(shard_name(1), "01");
+        assert_eq!(shard_name(4194303), "ff");
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = r#"set_output_dir("DIR"); set_shard(true); set_save_metadata(true);"#;
+        let opts = Opts {
+            pid: 258,
+            exe: "!usr!bin!foo".to_owned(),
+            ..crash_opts(dir.path(), &script.replace("DIR", &dir.path().to_string_lossy()))
+        };
+        handle_crash(&opts).unwrap();
+        let core = dir.path().join("02/core.258.foo");
+        assert_eq!(std::fs::read_to_string(&core).unwrap(), "0123456789");
+        let metadata: Metadata = serde_json::from_slice(&std::fs::read(with_suffix(&core, ".json")).unwrap()).unwrap();
+        assert_eq!(metadata.shard.as_deref(), Some("02"));
+    }
+
+    #[test]
+    fn test_ctx() {
+        // Nothing sets SELLAFIELD_CTX_* in the tests; see metadata's test for
+        // how they're read.
+        let config = run_test_script(r#"set_output_path("/tmp/" + ctx("cluster") + "core");"#).unwrap();
+        assert_eq!(config.output_path, "/tmp/core");
+    }
+
+    #[test]
+    fn test_set_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let script = format!(
+            r#"set_output_path("{}"); set_save_metadata(true); set_tag("service", "api"); set_tag("region", "eu");"#,
+            output.display()
+        );
+        handle_crash(&crash_opts(dir.path(), &script)).unwrap();
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("core.json")).unwrap()).unwrap();
+        assert_eq!(sidecar["tags"], serde_json::json!({"region": "eu", "service": "api"}));
+
+        let error = run_test_script(r#"set_tag("not ok", "x");"#).unwrap_err().to_string();
+        assert!(error.contains("invalid tag key"), "{}", error);
+    }
+
+    #[test]
+    fn test_siginfo_sidecar() {
+        use crate::elf::test::{elf, elf32, note};
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let script = format!(r#"set_output_path("{}"); set_save_metadata(true);"#, output.display());
+        let opts = Opts {
+            core_limit: 1000,
+            ..crash_opts(dir.path(), &script)
+        };
+        let input = opts.input.clone().unwrap();
+        let mut desc = [0u8; 128];
+        desc[0..4].copy_from_slice(&11i32.to_le_bytes());
+        desc[8..12].copy_from_slice(&2i32.to_le_bytes());
+        desc[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        std::fs::write(&input, elf(&[(4, 0, note(b"CORE", crate::elf::NT_SIGINFO, &desc))])).unwrap();
+        let sidecar = || -> serde_json::Value {
+            handle_crash(&opts).unwrap();
+            serde_json::from_slice(&std::fs::read(dir.path().join("core.json")).unwrap()).unwrap()
+        };
+        assert_eq!(sidecar()["siginfo"], serde_json::json!({"signo": 11, "code": 2, "fault_address": "0x1000"}));
+
+        // A 32-bit process's core.
+        let mut desc = [0u8; 128];
+        desc[0..4].copy_from_slice(&7i32.to_le_bytes());
+        desc[8..12].copy_from_slice(&1i32.to_le_bytes());
+        desc[12..16].copy_from_slice(&0x2000u32.to_le_bytes());
+        std::fs::write(&input, elf32(&[(4, 0, note(b"CORE", crate::elf::NT_SIGINFO, &desc))])).unwrap();
+        let sidecar32 = sidecar();
+        assert_eq!(sidecar32["siginfo"], serde_json::json!({"signo": 7, "code": 1, "fault_address": "0x2000"}));
+        assert_eq!(sidecar32["arch"], "i386");
+
+        // Not a real core.
+        std::fs::write(&input, "0123456789").unwrap();
+        let sidecar = sidecar();
+        assert!(sidecar.get("siginfo").is_none());
+        assert!(sidecar.get("arch").is_none());
+    }
+
+    #[test]
+    fn test_save_fds() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let script = format!(r#"set_output_path("{}"); set_save_metadata(true);"#, output.display());
+        // Pretend we're the crashed process.
+        let opts = Opts {
+            pid: std::process::id(),
+            ..crash_opts(dir.path(), &script)
+        };
+        let sidecar = || -> serde_json::Value {
+            handle_crash(&opts).unwrap();
+            serde_json::from_slice(&std::fs::read(dir.path().join("core.json")).unwrap()).unwrap()
+        };
+        assert!(sidecar().get("fds").is_none());
+
+        std::fs::write(&opts.config, script + "set_save_fds(true);").unwrap();
+        let metadata = sidecar();
+        let fds = metadata["fds"].as_object().unwrap();
+        assert!(fds.values().any(|target| target.as_str().unwrap().starts_with('/')), "{:?}", fds);
+    }
+
+    #[test]
+    fn test_input_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out/core");
+        let opts = Opts {
+            pid: 42,
+            time: 1700000000,
+            exe: "!usr!bin!foo".to_owned(),
+            core_limit: 4,
+            ..crash_opts(dir.path(), &format!("set_output_path(\"{}\");", output.display()))
+        };
+        handle_crash(&opts).unwrap();
+        // Still truncated to the core limit.
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "0123");
+    }
+
+    #[test]
+    fn test_min_free_inodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out/core");
+        let opts = crash_opts(
+            dir.path(),
+            &format!("set_output_path(\"{}\"); set_min_free_inodes(1 << 62);", output.display()),
+        );
+        // Some filesystems don't have an inode limit, in which case it's ignored.
+        if free_inodes(dir.path()).unwrap().is_some() {
+            assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+            assert!(!output.exists());
+        } else {
+            assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        }
+
+        let script = format!("set_output_path(\"{}\"); set_min_free_inodes(1);", output.display());
+        std::fs::write(&opts.config, script).unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        assert!(output.exists());
+    }
+
+    #[test]
+    fn test_dump_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let mut opts = Opts {
+            dump_mode: Some(2),
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}");"#, output.display()))
+        };
+
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+        assert!(!output.exists());
+
+        opts.dump_mode = Some(1);
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+
+        opts.dump_mode = Some(2);
+        std::fs::write(
+            &opts.config,
+            format!(r#"if dump_mode() == 2 {{ set_force_dump(true); }} set_output_path("{}");"#, output.display()),
+        )
+        .unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+    }
+
+    #[test]
+    fn test_signals() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        assert!(parse_signals("6,x").is_err());
+        let mut opts = Opts {
+            signals: Some(parse_signals("6, 11").unwrap()),
+            // Not /var/crash when the script fails.
+            fallback_dir: Some(dir.path().join("fallback")),
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}");"#, output.display()))
+        };
+
+        for (signal, outcome) in [(Some(11), Outcome::Written), (Some(3), Outcome::Skipped), (None, Outcome::Written)] {
+            opts.signal = signal;
+            assert_eq!(handle_crash(&opts).unwrap(), outcome, "{:?}", signal);
+        }
+
+        // The script wins.
+        opts.signal = Some(3);
+        std::fs::write(
+            &opts.config,
+            format!(r#"set_output_path("{}"); if signal == 3 {{ set_capture_signals([3]); }}"#, output.display()),
+        )
+        .unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        std::fs::write(&opts.config, "set_capture_signals([0]);").unwrap();
+        assert!(handle_crash(&opts).is_err());
+    }
+
+    #[test]
+    fn test_add_output() {
+        let dir = tempfile::tempdir().unwrap();
+        // Can't create a directory under a file.
+        let blocker = dir.path().join("blocker");
+        std::fs::write(&blocker, "").unwrap();
+        let first = dir.path().join("a/core");
+        let second = dir.path().join("b/core");
+        let script = format!(
+            r#"set_output_path("{}"); add_output("{}", 0o640); add_output("{}/c/core"); set_permissions(0o400);"#,
+            first.display(),
+            second.display(),
+            blocker.display()
+        );
+        let opts = crash_opts(dir.path(), &script);
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        for (path, mode) in [(&first, 0o400), (&second, 0o640)] {
+            assert_eq!(std::fs::read_to_string(path).unwrap(), "0123456789");
+            assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o7777, mode);
+        }
+
+        // It's only an error if they all fail.
+        std::fs::write(&opts.config, format!(r#"add_output("{}/c/core");"#, blocker.display())).unwrap();
+        assert!(handle_crash(&opts).is_err());
+    }
+
+    #[test]
+    fn test_base_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = r#"set_output_path("cores/core"); add_output("/dev/null/../../cores/second");"#;
+        let base = dir.path().join("base");
+        std::fs::create_dir(&base).unwrap();
+        let opts = Opts {
+            base_dir: Some(base.clone()),
+            ..crash_opts(dir.path(), script)
+        };
+        let mut resolved = run_script(&opts, "", "", &get_user_details(opts.uid).unwrap(), &ProcessInfo::default())
+            .unwrap();
+        resolved.resolve_relative(&base);
+        assert_eq!(resolved.output_path, base.join("cores/core").to_string_lossy());
+        // Absolute paths are left alone.
+        assert_eq!(resolved.extra_outputs[0].path, Path::new("/dev/null/../../cores/second"));
+
+        std::fs::write(&opts.config, r#"set_output_path("cores/core");"#).unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        assert_eq!(std::fs::read_to_string(base.join("cores/core")).unwrap(), "0123456789");
+    }
+
+    #[test]
+    fn test_gzip_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.rhai.gz");
+        std::fs::write(&config, crate::gzip::test::CONFIG_GZ).unwrap();
+        let opts = Opts { uid: unsafe { libc::getuid() }, config, ..Default::default() };
+        let user = get_user_details(opts.uid).unwrap();
+        let config = run_script(&opts, "", "", &user, &ProcessInfo::default()).unwrap();
+        assert_eq!(config.output_path, "/tmp/core");
+
+        std::fs::write(&opts.config, &crate::gzip::test::CONFIG_GZ[..40]).unwrap();
+        let error = run_script(&opts, "", "", &user, &ProcessInfo::default()).unwrap_err();
+        assert!(error.to_string().contains("config.rhai.gz"), "{}", error);
+    }
+
+    #[test]
+    fn test_post_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let marker = dir.path().join("marker");
+        let hook = format!(r#"cat \"$1\" $SELLAFIELD_METADATA > {}; exit 1"#, marker.display());
+        let script =
+            format!(r#"set_output_path("{}"); set_save_metadata(true); set_post_hook("{}");"#, output.display(), hook);
+        let opts = Opts {
+            pid: 42,
+            ..crash_opts(dir.path(), &script)
+        };
+        // The hook failing doesn't matter.
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        let seen = std::fs::read_to_string(&marker).unwrap();
+        assert!(seen.starts_with("0123456789{") && seen.contains(r#""pid": 42"#), "{}", seen);
+    }
+
+    #[test]
+    fn test_max_total_dumps() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let opts = Opts {
+            max_total_dumps: Some(2),
+            // Not /var/crash when the script fails.
+            fallback_dir: Some(dir.path().join("fallback")),
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}");"#, output.display()))
+        };
+        for outcome in [Outcome::Written, Outcome::Written, Outcome::Skipped] {
+            assert_eq!(handle_crash(&opts).unwrap(), outcome);
+        }
+        assert_eq!(counter::reset_dir(&opts.lock_dir).unwrap(), 2);
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+
+        // Cores that aren't written don't count.
+        let script = std::fs::read_to_string(&opts.config).unwrap();
+        std::fs::write(&opts.config, "skip();").unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+        std::fs::write(&opts.config, "this is not rhai").unwrap();
+        assert!(handle_crash(&opts).is_err());
+        std::fs::write(&opts.config, script).unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        assert_eq!(counter::reset_dir(&opts.lock_dir).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_decode_exe() {
+        assert_eq!(decode_exe("!usr!bin!foo!", ExeEncoding::Bang), "/usr/bin/foo/");
+        assert_eq!(decode_exe("/opt/hey!/foo", ExeEncoding::Raw), "/opt/hey!/foo");
+        assert_eq!("raw".parse(), Ok(ExeEncoding::Raw));
+        assert!("slash".parse::<ExeEncoding>().is_err());
+
+        // Nasty inputs don't panic, and the name never has a path in it.
+        let nasty = [
+            "", "!", "!!", "!..", "!.", "..!..!", "!usr!bin!", "foo", "!a\u{0}b!c", "!x!!y", "!\u{202e}gpj.exe", "é!ü",
+        ];
+        for exe in nasty {
+            for encoding in [ExeEncoding::Bang, ExeEncoding::Raw] {
+                let name = exe_name(&decode_exe(exe, encoding));
+                assert!(!name.contains('/'), "{:?} -> {:?}", exe, name);
+                assert!(name != "." && name != "..", "{:?} -> {:?}", exe, name);
+            }
+            assert!(!decode_exe(exe, ExeEncoding::Bang).contains('!'), "{:?}", exe);
+        }
+        assert_eq!(exe_name("/usr/bin/foo"), "foo");
+        // The kernel always gives a full path; anything else has no name.
+        assert_eq!(exe_name("foo"), "");
+        assert_eq!(exe_name("/usr/bin/.."), "");
+        assert_eq!(exe_name("/usr/bin/..foo"), "..foo");
+    }
+
+    #[test]
+    fn test_mb_per_sec() {
+        assert_eq!(mb_per_sec(3_000_000, Duration::from_secs(2)), 1.5);
+        assert_eq!(mb_per_sec(1_000_000, Duration::ZERO), 1000.0);
+        assert_eq!(mb_per_sec(0, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_packed() {
+        let args = |args: &[&str]| unpack_args(args.iter().map(|arg| arg.to_string()).collect());
+
+        let unpacked = args(&["sellafield", "-P", "1000:42:1700000000:4096:1:!usr!bin!a:b"]).unwrap();
+        let opts: Opts = try_from_args(&unpacked).unwrap();
+        assert_eq!((opts.uid, opts.pid, opts.time, opts.core_limit), (1000, 42, 1700000000, 4096));
+        assert_eq!(opts.dump_mode, Some(1));
+        assert_eq!(opts.exe, "!usr!bin!a:b");
+
+        let error = args(&["sellafield", "--packed", "1000:42"]).unwrap_err().to_string();
+        assert!(error.contains("has 2 fields but should have 6"), "{}", error);
+        // Bad numbers are caught by the normal parsing.
+        let unpacked = args(&["sellafield", "--packed", "x:42:1:1:1:!a"]).unwrap();
+        assert!(try_from_args::<Opts>(&unpacked).is_err());
+    }
+
+    #[test]
+    fn test_comm() {
+        let args = ["sellafield", "-u", "0", "-p", "1", "-t", "1", "-E", "!usr!bin!foo", "-c", "1", "-e", "worker 3"];
+        let opts: Opts = try_from_args(&args.map(String::from)).unwrap();
+        assert_eq!(opts.comm.as_deref(), Some("worker 3"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.rhai");
+        std::fs::write(&config, r#"if comm() == comm && exe() == "foo" { set_output_path("/tmp/" + comm); }"#).unwrap();
+        let opts = Opts { uid: unsafe { libc::getuid() }, config, ..opts };
+        let user = get_user_details(opts.uid).unwrap();
+        let resolved = run_script(&opts, "/usr/bin/foo", "foo", &user, &ProcessInfo::default()).unwrap();
+        assert_eq!(resolved.output_path, "/tmp/worker 3");
+    }
+
+    #[test]
+    fn test_fallback_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback_dir = dir.path().join("fallback");
+        let fallback = fallback_dir.join(format!("core.42.1700000000.{}", capture_time_ms()));
+        let opts = Opts {
+            pid: 42,
+            time: 1700000000,
+            fallback_dir: Some(fallback_dir),
+            ..crash_opts(dir.path(), "")
+        };
+        let config = opts.config.clone();
+
+        for script in ["this is not rhai", "let x = 1;"] {
+            std::fs::write(&config, script).unwrap();
+            let _ = std::fs::remove_file(&fallback);
+            let _ = handle_crash(&opts);
+            assert_eq!(std::fs::read_to_string(&fallback).unwrap(), "0123456789", "{}", script);
+            assert_eq!(std::fs::metadata(&fallback).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Deliberately skipped.
+        std::fs::write(&config, r#"set_output_path("");"#).unwrap();
+        std::fs::remove_file(&fallback).unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+        assert!(!fallback.exists());
+    }
+
+    #[test]
+    fn test_script_fallback_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = Opts::default();
+        // Without --fallback-dir a failing script still writes to the default.
+        assert_eq!(script_fallback_dir(&opts, dir.path()), Some(dir.path().to_owned()));
+        assert_eq!(script_fallback_dir(&opts, &dir.path().join("missing")), None);
+        opts.fallback_dir = Some(dir.path().join("fallback"));
+        assert_eq!(script_fallback_dir(&opts, &dir.path().join("missing")), opts.fallback_dir);
+    }
+
+    #[test]
+    fn test_same_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        assert!(same_filesystem(dir.path(), &dir.path().join("sub")));
+        assert!(!same_filesystem(dir.path(), &dir.path().join("missing")));
+        // /proc is always its own filesystem.
+        assert!(!same_filesystem(dir.path(), Path::new("/proc")));
+    }
+
+    #[test]
+    fn test_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let cores = dir.path().join("cores");
+        let script = format!(r#"set_output_path("{}/core." + pid); set_debounce_secs(60);"#, cores.display());
+        let mut opts = Opts {
+            pid: 1,
+            time: 1000,
+            exe: "!usr!bin!foo".to_owned(),
+            ..crash_opts(dir.path(), &script)
+        };
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        opts.pid = 2;
+        opts.time = 1030;
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+        opts.pid = 3;
+        opts.time = 1061;
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        assert!(!dir.path().join("cores/core.2").exists());
+        assert!(opts.lock_dir.join(crate::debounce::STATE_FILE).exists());
+        assert_eq!(std::fs::read_dir(&cores).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let dir = tempfile::tempdir().unwrap();
+        let cores = dir.path().join("cores");
+        let script =
+            format!(r#"set_output_path("{}/core." + pid); set_keep_first(2); set_sample_rate(3);"#, cores.display());
+        let mut opts = Opts {
+            exe: "!usr!bin!foo".to_owned(),
+            ..crash_opts(dir.path(), &script)
+        };
+        let mut kept = Vec::new();
+        for pid in 0..8 {
+            opts.pid = pid;
+            if handle_crash(&opts).unwrap() == Outcome::Written {
+                kept.push(pid);
+            }
+        }
+        assert_eq!(kept, [0, 1, 4, 7]);
+        assert!(!cores.join("core.2").exists());
+        // The counts are in the lock directory, not with the cores.
+        assert!(opts.lock_dir.join(crate::sampling::STATE_FILE).exists());
+    }
+
+    #[test]
+    fn test_storm_auto_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = format!(
+            r#"set_output_path("{}/core." + pid); set_storm_threshold(3); set_storm_auto_sample(true);"#,
+            dir.path().join("cores").display()
+        );
+        let mut opts = Opts {
+            exe: "!usr!bin!foo".to_owned(),
+            ..crash_opts(dir.path(), &script)
+        };
+        let mut kept = Vec::new();
+        // The storm starts with the third crash, and then one a minute is kept.
+        for (pid, time) in [1000, 1001, 1002, 1003, 1004, 1030, 1062].into_iter().enumerate() {
+            opts.pid = pid as u32;
+            opts.time = time;
+            if handle_crash(&opts).unwrap() == Outcome::Written {
+                kept.push(time);
+            }
+        }
+        assert_eq!(kept, [1000, 1001, 1002, 1062]);
+        assert!(opts.lock_dir.join(crate::storm::STATE_FILE).exists());
+    }
+
+    #[test]
+    fn test_require_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = crash_opts(dir.path(), "let x = 1;");
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+        opts.require_output = true;
+        let error = handle_crash(&opts).unwrap_err();
+        assert!(error.to_string().contains("didn't call set_output_path() or skip()"), "{}", error);
+        assert_eq!(error_exit_code(&error), 20);
+
+        for script in ["skip();", r#"set_output_path("");"#, r#"set_output_path("/nowhere"); skip();"#] {
+            std::fs::write(&opts.config, script).unwrap();
+            assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped, "{}", script);
+        }
+    }
+
+    #[test]
+    fn test_split_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("cores/core");
+        let opts = crash_opts(dir.path(), "");
+        for (bytes, chunks) in [(4, vec!["0123", "4567", "89"]), (5, vec!["01234", "56789"])] {
+            std::fs::write(
+                &opts.config,
+                format!(
+                    r#"set_output_path("{}"); set_split_bytes({}); set_save_metadata(true);"#,
+                    output.display(),
+                    bytes
+                ),
+            )
+            .unwrap();
+            assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+            for (i, chunk) in chunks.iter().enumerate() {
+                assert_eq!(std::fs::read_to_string(chunk_path(&output, i as u32)).unwrap(), *chunk);
+            }
+            assert!(!chunk_path(&output, chunks.len() as u32).exists());
+            assert!(!output.exists());
+            let json = std::fs::read(with_suffix(&output, ".json")).unwrap();
+            let metadata: Metadata = serde_json::from_slice(&json).unwrap();
+            assert_eq!(metadata.chunks, Some(chunks.len() as u32));
+
+            // Retention deletes all the chunks.
+            let policy = retention::Policy {
+                max_age_days: Some(1),
+                ..Default::default()
+            };
+            assert_eq!(retention::apply(&dir.path().join("cores"), &policy, 1_000_000, false).unwrap().len(), 1);
+            assert_eq!(std::fs::read_dir(dir.path().join("cores")).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_save_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let opts = crash_opts(dir.path(), "");
+        for (bytes, expected) in [(4, "0123"), (1000, "0123456789")] {
+            let script = format!(r#"set_output_path("{}"); set_save_header({});"#, output.display(), bytes);
+            std::fs::write(&opts.config, script).unwrap();
+            handle_crash(&opts).unwrap();
+            assert_eq!(std::fs::read_to_string(dir.path().join("core.head")).unwrap(), expected);
+        }
+
+        // The core still counts as written if the header can't be.
+        std::fs::remove_file(dir.path().join("core.head")).unwrap();
+        std::fs::create_dir(dir.path().join("core.head")).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "0123456789");
+    }
+
+    #[test]
+    fn test_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let truncated = with_suffix(&output, ".truncated");
+        let opts = crash_opts(dir.path(), "");
+        let run = |max_size: &str| {
+            let script = format!(r#"set_output_path("{}"); set_max_size({});"#, output.display(), max_size);
+            std::fs::write(&opts.config, script).unwrap();
+            handle_crash(&opts).unwrap()
+        };
+
+        // Under the cap it's kept as normal.
+        assert_eq!(run("10"), Outcome::Written);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "0123456789");
+        std::fs::remove_file(&output).unwrap();
+
+        // Over it the partial core is deleted by default.
+        for max_size in ["4", r#"4, "delete""#] {
+            assert_eq!(run(max_size), Outcome::Skipped, "{}", max_size);
+            assert!(!output.exists(), "{}", max_size);
+            assert!(!truncated.exists(), "{}", max_size);
+        }
+
+        // Or kept, cut short and marked.
+        assert_eq!(run(r#"4, "truncate""#), Outcome::Written);
+        assert_eq!(std::fs::read_to_string(&truncated).unwrap(), "0123");
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_save_proc() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let script = format!(r#"set_output_path("{}"); set_permissions(0o640);"#, output.display());
+        // Pretend we're the crashed process.
+        let opts = Opts {
+            pid: std::process::id(),
+            ..crash_opts(dir.path(), &script)
+        };
+        let sidecars = [with_suffix(&output, ".status"), with_suffix(&output, ".stat")];
+        handle_crash(&opts).unwrap();
+        for path in &sidecars {
+            assert!(!path.exists(), "{}", path.display());
+        }
+
+        let script = script + "set_save_proc(true);";
+        std::fs::write(&opts.config, &script).unwrap();
+        handle_crash(&opts).unwrap();
+        for path in &sidecars {
+            let contents = std::fs::read_to_string(path).unwrap();
+            assert!(!contents.is_empty(), "{}", path.display());
+            assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o7777, 0o640, "{}", path.display());
+        }
+        assert!(std::fs::read_to_string(&sidecars[0]).unwrap().starts_with("Name:"));
+        assert!(std::fs::read_to_string(&sidecars[1]).unwrap().starts_with(&format!("{} (", std::process::id())));
+
+        // Nothing to save if the process has already gone.
+        for path in &sidecars {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(handle_crash(&Opts { pid: 0, ..crash_opts(dir.path(), &script) }).unwrap(), Outcome::Written);
+        for path in &sidecars {
+            assert!(!path.exists(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = format!("set_output_path(\"{}\");", dir.path().join("core").display());
+        let mut opts = Opts {
+            // Not /var/crash when the script fails.
+            fallback_dir: Some(dir.path().join("fallback")),
+            ..crash_opts(dir.path(), &script)
+        };
+        let config = opts.config.clone();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Written);
+
+        std::fs::write(&config, "set_output_path(\"\");").unwrap();
+        assert_eq!(handle_crash(&opts).unwrap(), Outcome::Skipped);
+
+        std::fs::write(&config, "this is not rhai").unwrap();
+        assert_eq!(error_exit_code(&handle_crash(&opts).unwrap_err()), 20);
+        assert!(opts.fallback_dir.as_ref().unwrap().read_dir().unwrap().next().is_some());
+
+        opts.core_limit = 1;
+        assert_eq!(handle_crash(&opts).unwrap().exit_code(), 11);
+
+        let disk_full = anyhow::Error::from(std::io::Error::from_raw_os_error(libc::ENOSPC)).context("writing core");
+        assert_eq!(error_exit_code(&disk_full), 12);
+        assert_eq!(error_exit_code(&anyhow::anyhow!("something else")), 1);
+    }
+
+    #[test]
+    fn test_deadline() {
+        use std::{io::Write, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("core");
+        let mut opts = Opts {
+            deadline: 1,
+            core_limit: 1 << 20,
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}");"#, output.display()))
+        };
+        // A reader that starts the core and then never finishes it.
+        let fifo = dir.path().join("slow");
+        let c_fifo = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_fifo.as_ptr(), 0o600) }, 0);
+        opts.input = Some(fifo.clone());
+
+        // In a child because the watchdog exits the process.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let _ = handle_crash(&opts);
+            unsafe { libc::_exit(0) };
+        }
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+        writer.write_all(&[0; 4096]).unwrap();
+        // It's written some of it before the deadline.
+        for _ in 0..50 {
+            if output.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(output.exists());
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status), "{}", status);
+        assert_eq!(libc::WEXITSTATUS(status), 14);
+        drop(writer);
+        // The partial core was deleted.
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_home_fallback_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = dir.path().join("fallback");
+        let missing_home = dir.path().join("missing");
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+
+        assert_eq!(
+            home_fallback_path(&missing_home.join("cores/core.1"), &missing_home, Some(&fallback)),
+            Some(fallback.join("core.1"))
+        );
+        // No fallback configured.
+        assert_eq!(home_fallback_path(&missing_home.join("cores/core.1"), &missing_home, None), None);
+        // Home exists.
+        assert_eq!(home_fallback_path(&home.join("cores/core.1"), &home, Some(&fallback)), None);
+        // Not writing to home.
+        assert_eq!(home_fallback_path(Path::new("/var/cores/core.1"), &missing_home, Some(&fallback)), None);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let read_only = dir.path().join("ro");
+        let fallback = dir.path().join("fallback");
+        std::fs::create_dir(&read_only).unwrap();
+        // Needs root; skip it otherwise.
+        let mount = |args: &[&str]| std::process::Command::new("mount").args(args).status().is_ok_and(|s| s.success());
+        let ro = read_only.to_str().unwrap();
+        if !mount(&["--bind", ro, ro]) {
+            return;
+        }
+        let mounted = mount(&["-o", "remount,bind,ro", ro]);
+        let opts = Opts {
+            fallback_dir: Some(fallback.clone()),
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}/cores/core");"#, ro))
+        };
+        let result = handle_crash(&opts);
+        std::process::Command::new("umount").arg(ro).status().unwrap();
+        assert!(mounted);
+
+        let error = result.unwrap_err();
+        assert!(is_read_only(&error), "{:#}", error);
+        let name = format!("core.0.0.{}", capture_time_ms());
+        assert_eq!(std::fs::read_to_string(fallback.join(name)).unwrap(), "0123456789");
+    }
+
+    #[test]
+    fn test_drop_privileges() {
+        // Needs root, and a user to switch to.
+        let nobody = match get_user_details(65534) {
+            Ok(nobody) if unsafe { libc::geteuid() } == 0 => nobody,
+            _ => return,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        let core = dir.path().join("cores/deeper/core");
+
+        // Do it in a child so the rest of the tests stay root.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let ok = drop_privileges(65534, &nobody, false).is_ok() && open_output(&core, 0o600).is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert_eq!(status, 0);
+
+        for path in [dir.path().join("cores"), dir.path().join("cores/deeper"), core] {
+            let meta = std::fs::metadata(&path).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (65534, nobody.gid), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_privileged_write() {
+        // Needs root, and a user to switch to.
+        let nobody = match get_user_details(65534) {
+            Ok(nobody) if unsafe { libc::geteuid() } == 0 => nobody,
+            _ => return,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let root_only = dir.path().join("crash");
+        std::fs::create_dir(&root_only).unwrap();
+        std::fs::set_permissions(&root_only, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let opts = Opts {
+            uid: 65534,
+            ..crash_opts(dir.path(), "")
+        };
+        let config = opts.config.clone();
+
+        // In a child so the rest of the tests stay root. It mustn't be able to
+        // get root back afterwards either way.
+        let run = |script: String| {
+            std::fs::write(&config, script).unwrap();
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                let written = handle_crash(&opts).is_ok_and(|outcome| outcome == Outcome::Written);
+                let (mut ruid, mut euid, mut suid) = (0, 0, 0);
+                unsafe { libc::getresuid(&mut ruid, &mut euid, &mut suid) };
+                let dropped = (ruid, euid, suid) == (65534, 65534, 65534);
+                unsafe { libc::_exit(if written && dropped { 0 } else { 1 }) };
+            }
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            status == 0
+        };
+        let core = root_only.join("core");
+        let script = format!(r#"set_output_path("{}"); set_save_metadata(true);"#, core.display());
+        assert!(!run(script.clone()));
+        assert!(!core.exists());
+
+        assert!(run(format!("{} set_privileged_write(true);", script)));
+        for path in [core.clone(), with_suffix(&core, ".json")] {
+            let meta = std::fs::metadata(&path).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (65534, nobody.gid), "{}", path.display());
+        }
+
+        // Not needed if the user can write there anyway.
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        std::os::unix::fs::chown(&home, Some(65534), None).unwrap();
+        assert!(run(format!(r#"set_output_path("{}/core"); set_privileged_write(true);"#, home.display())));
+    }
+
+    #[test]
+    fn test_output_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let cores = dir.path().join("cores");
+        std::fs::create_dir(&cores).unwrap();
+        let mut opts = Opts {
+            output_uid: Some(3_999_999_999),
+            ..crash_opts(dir.path(), &format!(r#"set_output_path("{}/core." + uid);"#, cores.display()))
+        };
+        let error = format!("{:#}", handle_crash(&opts).unwrap_err());
+        assert!(error.starts_with("invalid --output-uid: User ID 3999999999 not found"), "{}", error);
+        opts.output_uid = None;
+        opts.output_gid = Some(3_999_999_999);
+        let error = handle_crash(&opts).unwrap_err().to_string();
+        assert_eq!(error, "invalid --output-gid: group ID 3999999999 not found in the group database");
+
+        // The rest needs root, and a user to switch to.
+        let nobody = match get_user_details(65534) {
+            Ok(nobody) if unsafe { libc::geteuid() } == 0 => nobody,
+            _ => return,
+        };
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::chown(&cores, Some(65534), None).unwrap();
+        opts.output_uid = Some(65534);
+        opts.output_gid = None;
+        // In a child so the rest of the tests stay root.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let written = handle_crash(&opts).is_ok_and(|outcome| outcome == Outcome::Written);
+            unsafe { libc::_exit(if written { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert_eq!(status, 0);
+        // Routed by the crashing user but owned by the output user.
+        let meta = std::fs::metadata(cores.join("core.0")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (65534, nobody.gid));
+    }
+
+    #[test]
+    fn test_get_user_details() {
+        assert_eq!(get_user_details(0).unwrap().gid, 0);
+        // Hopefully nobody has this one.
+        let error = get_user_details(3_999_999_999).err().unwrap().to_string();
+        assert!(error.contains("not found"), "{}", error);
+    }
+
+    #[test]
+    fn test_groups() {
+        // Root is always in the root group (gid 0), whatever it's called.
+        let root = get_user_details(0).unwrap();
+        assert!(!get_group_names(&root.username, root.gid).is_empty());
+        assert!(get_group_names("sellafield_no_such_user", 12345).len() <= 1);
+
+        run_test_script(r#"let g = groups(); if !(g[0] in g) { throw "not found"; }"#).unwrap();
+    }
+
+    #[test]
+    fn test_use_crash_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core");
+        let config = Config {
+            output_path: path.to_string_lossy().to_string(),
+            permissions: 0o600,
+            use_crash_time: true,
+            ..Default::default()
+        };
+        let opts = Opts {
+            core_limit: 100,
+            time: 1700000000,
+            ..Default::default()
+        };
+        write_output(&config, &opts, &mut "core".as_bytes()).unwrap();
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(mtime, std::time::UNIX_EPOCH + std::time::Duration::from_secs(1700000000));
+    }
+
+    #[test]
+    fn test_empty_core() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core");
+        let mut config = Config {
+            output_path: path.to_string_lossy().to_string(),
+            permissions: 0o600,
+            ..Default::default()
+        };
+        let opts = Opts { core_limit: 100, ..Default::default() };
+        assert!(write_output(&config, &opts, &mut "".as_bytes()).unwrap().is_empty());
+        assert!(!path.exists());
+
+        config.keep_empty = true;
+        let cores = write_output(&config, &opts, &mut "".as_bytes()).unwrap();
+        assert_eq!(cores.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_latest_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("foo.latest");
+        let elsewhere = dir.path().join("elsewhere");
+        std::fs::write(&elsewhere, "precious").unwrap();
+        // Pretend someone planted a link.
+        std::os::unix::fs::symlink(&elsewhere, &link).unwrap();
+
+        for name in ["core.1.foo", "core.2.foo"] {
+            let core = dir.path().join(name);
+            std::fs::write(&core, name).unwrap();
+            update_latest_symlink(&core, "foo", 1).unwrap();
+            assert_eq!(std::fs::read_link(&link).unwrap(), Path::new(name));
+            assert_eq!(std::fs::read_to_string(&link).unwrap(), name);
+        }
+        assert_eq!(std::fs::read_to_string(&elsewhere).unwrap(), "precious");
+        assert!(!dir.path().join(".foo.latest.1").exists());
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(written_mode(0o640, 0o022), 0o640);
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let conflicts = |script: &str| run_test_script(script).unwrap().conflicts();
+        assert!(conflicts(r#"set_output_path("/tmp/core"); set_save_metadata(true); set_tag("a", "b");"#).is_empty());
+
+        let script = r#"
+            set_output_path("/tmp/core");
+            add_output("/tmp/core");
+            set_tag("a", "b");
+            set_capture_env(true);
+            set_max_total_bytes(1000);
+            set_storm_auto_sample(true);
+            set_post_hook_timeout_secs(5);
+            set_max_size(100);
+            set_split_bytes(100);
+        "#;
+        assert_eq!(
+            conflicts(script),
+            [
+                "set_tag() does nothing without set_save_metadata(true)",
+                "set_capture_env(true) does nothing without set_save_metadata(true)",
+                "the retention policy never deletes anything without set_save_metadata(true), since it only \
+                 considers cores with a sidecar",
+                "set_storm_auto_sample(true) does nothing without set_storm_threshold()",
+                "set_post_hook_timeout_secs() does nothing without set_post_hook()",
+                "set_split_bytes(100) never splits anything because cores are limited to set_max_size(100)",
+                "/tmp/core is written more than once; check set_output_path() and add_output()",
+            ]
+        );
+        assert_eq!(
+            conflicts(r#"set_save_metadata(true); set_retention_dir("/tmp");"#),
+            ["set_retention_dir() does nothing without a set_max_*() retention policy"]
+        );
+    }
+
+    #[test]
+    fn test_no_owner_read() {
+        let modes = |script: &str| -> Vec<u64> {
+            let outputs = r#"set_output_path("/tmp/core"); add_output("/tmp/copy", 0o040);"#;
+            let config = run_test_script(&format!("{} {}", outputs, script));
+            config.unwrap().outputs().iter().map(|output| output.permissions).collect()
+        };
+        // Only a warning by default.
+        assert_eq!(modes("set_permissions(0o044);"), [0o044, 0o040]);
+        assert_eq!(modes("set_permissions(0o044); set_ensure_owner_read(true);"), [0o444, 0o440]);
+        assert_eq!(modes("set_permissions(0o640); set_ensure_owner_read(true);"), [0o640, 0o440]);
+    }
+
+    #[test]
+    fn test_does_not_follow_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        std::fs::write(&target, "precious").unwrap();
+        let path = dir.path().join("core");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        let config = Config {
+            output_path: path.to_string_lossy().to_string(),
+            permissions: 0o600,
+            ..Default::default()
+        };
+        write_output(&config, &limit(100), &mut "core".as_bytes()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "precious");
+        assert!(std::fs::symlink_metadata(&path).unwrap().file_type().is_file());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "core");
+    }
+
+    #[test]
+    fn test_bytes_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            output_path: dir.path().join("core").to_string_lossy().to_string(),
+            permissions: 0o600,
+            ..Default::default()
+        };
+        let core = write_output(&config, &limit(100), &mut "core".as_bytes()).unwrap().remove(0);
+        assert_eq!(core.bytes, 4);
+        assert!(!core.truncated);
+
+        // Truncated by the core limit.
+        let core = write_output(&config, &limit(2), &mut "core".as_bytes()).unwrap().remove(0);
+        assert_eq!(core.bytes, 2);
+        assert!(core.truncated);
+
+        // Exactly the core limit.
+        let core = write_output(&config, &limit(4), &mut "core".as_bytes()).unwrap().remove(0);
+        assert_eq!(core.bytes, 4);
+        assert!(!core.truncated);
+    }
+
+    #[test]
+    fn test_mode_ignores_umask() {
+        for umask in [0o000, 0o002, 0o022] {
+            assert_eq!(written_mode(0o404, umask), 0o404);
+            assert_eq!(written_mode(0o644, umask), 0o644);
+            assert_eq!(written_mode(0o400, umask), 0o400);
+        }
+    }
+
+    #[test]
+    fn test_every_mode() {
+        // There are few enough to try every one check_mode() allows,
+        // including 0, 0o777 and the sticky bit. The core is written after
+        // the mode is set, which is fine because setuid and setgid (which an
+        // unprivileged write would clear) aren't allowed.
+        let umasks = [0o000, 0o022, 0o002];
+        for mode in 0..=0o1777 {
+            let umask = umasks[mode as usize % umasks.len()];
+            assert_eq!(written_mode(mode, umask), mode as u32, "mode {:o} umask {:o}", mode, umask);
+        }
+    }
+}
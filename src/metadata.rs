@@ -0,0 +1,289 @@
+//! The JSON sidecar written next to a core as `<core>.json`.
+
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ffi::OsString, path::PathBuf};
+
+use crate::{elf, glob, with_suffix};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub uid: u32,
+    pub username: String,
+    pub pid: u32,
+    /// Crash time from `%t`.
+    pub time: u32,
+    /// When sellafield started handling the crash, in Unix milliseconds, to
+    /// tell apart crashes in the same second. `time` is the crash time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture_time_ms: Option<u64>,
+    pub exe: String,
+    pub full_exe: String,
+    /// The crashed process's core size soft limit from `%c`.
+    pub core_limit: u64,
+    /// Number of bytes of core actually written.
+    pub core_bytes: u64,
+    /// Whether the core was cut short by `core_limit` or `set_max_size()`,
+    /// as opposed to just being small.
+    #[serde(default)]
+    pub truncated: bool,
+    /// Set by the script with `set_tag()`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// `SELLAFIELD_CTX_*` variables from our own environment, as `ctx()`
+    /// sees them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub context: BTreeMap<String, String>,
+    /// With `set_split_bytes()`, the number of `<core>.NNN` chunks the core
+    /// was written as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<u32>,
+    /// The signal, `si_code` and fault address from the core's `NT_SIGINFO`
+    /// note, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub siginfo: Option<elf::Siginfo>,
+    /// The crashed process's architecture from the core's ELF header, e.g.
+    /// `i386` for a 32-bit process on an `x86_64` host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// With `set_shard(true)`, the shard directory the core is in, e.g. `2a`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<String>,
+    /// The process's environment if `set_capture_env(true)`, redacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<BTreeMap<String, String>>,
+    /// The process's arguments if `set_capture_cmdline(true)`, redacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<Vec<String>>,
+    /// The process's open files if `set_save_fds(true)`, by fd number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fds: Option<BTreeMap<u32, String>>,
+}
+
+/// Variables that are always redacted, matched case-insensitively.
+pub const DEFAULT_REDACT: &[&str] = &["*PASSWORD*", "*PASSWD*", "*SECRET*", "*TOKEN*", "*KEY*", "*CREDENTIAL*"];
+
+pub const REDACTED: &str = "<redacted>";
+
+fn redact_name(name: &str, patterns: &[String]) -> bool {
+    let name = name.to_uppercase();
+    DEFAULT_REDACT
+        .iter()
+        .copied()
+        .chain(patterns.iter().map(String::as_str))
+        .any(|pattern| glob::matches(&pattern.to_uppercase(), &name))
+}
+
+/// `NAME=value` strings as a map, with the values of secret-looking names
+/// replaced by `REDACTED`.
+pub fn redact_env(vars: &[String], patterns: &[String]) -> BTreeMap<String, String> {
+    vars.iter()
+        .map(|var| {
+            let (name, value) = var.split_once('=').unwrap_or((var, ""));
+            let value = if redact_name(name, patterns) { REDACTED } else { value };
+            (name.to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+/// Prefix of our own environment variables that a wrapper around sellafield
+/// can set to pass context to the script, e.g. `SELLAFIELD_CTX_CLUSTER`.
+pub const CONTEXT_PREFIX: &str = "SELLAFIELD_CTX_";
+
+/// The `SELLAFIELD_CTX_*` variables in `vars` by lowercased name without the
+/// prefix, so `SELLAFIELD_CTX_CLUSTER` is `cluster`. Ones that aren't UTF-8
+/// are ignored.
+pub fn wrapper_context(vars: impl Iterator<Item = (OsString, OsString)>) -> BTreeMap<String, String> {
+    vars.filter_map(|(name, value)| {
+        let name = name.into_string().ok()?.strip_prefix(CONTEXT_PREFIX)?.to_lowercase();
+        Some((name, value.into_string().ok()?))
+    })
+    .filter(|(name, _)| !name.is_empty())
+    .collect()
+}
+
+/// Arguments with the value of any `--name=value` (or `name=value`) argument
+/// with a secret-looking name replaced. Values passed as a separate argument
+/// can't be recognised.
+pub fn redact_cmdline(args: &[String], patterns: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((name, _)) if redact_name(name.trim_start_matches('-'), patterns) => {
+                format!("{}={}", name, REDACTED)
+            }
+            _ => arg.clone(),
+        })
+        .collect()
+}
+
+/// Check a key passed to `set_tag()`. They're restricted to identifier-ish
+/// names so they're easy to filter on downstream.
+pub fn check_tag_key(key: &str) -> Result<(), String> {
+    let valid = key.len() <= 64
+        && key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        return Err(format!(
+            "invalid tag key '{}'; expected up to 64 letters, digits, '_', '-' or '.', starting with a letter or '_'",
+            key
+        ));
+    }
+    Ok(())
+}
+
+#[derive(FromArgs)]
+/// Show what's known about a core: its sidecar if it has one, otherwise
+/// whatever can be worked out from its name.
+#[argh(subcommand, name = "show")]
+pub struct ShowOpts {
+    /// path to the core.
+    #[argh(positional)]
+    core: PathBuf,
+}
+
+pub fn show(opts: &ShowOpts) -> Result<()> {
+    // Accept the sidecar path too.
+    let core = if opts.core.extension().is_some_and(|ext| ext == "json") {
+        opts.core.with_extension("")
+    } else {
+        opts.core.clone()
+    };
+
+    println!("Core: {}", core.display());
+    match core.symlink_metadata() {
+        Ok(meta) => println!("Size: {} bytes", meta.len()),
+        Err(_) => println!("Size: (core is missing)"),
+    }
+
+    let file_name = core.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let (_, encodings) = strip_encodings(&file_name);
+    for description in encodings {
+        println!("Encoding: {}", description);
+    }
+
+    let sidecar = with_suffix(&core, ".json");
+    match fs::read(&sidecar) {
+        Ok(json) => {
+            let metadata: serde_json::Value =
+                serde_json::from_slice(&json).with_context(|| format!("invalid sidecar {}", sidecar.display()))?;
+            println!("Sidecar: {}", sidecar.display());
+            println!("{}", serde_json::to_string_pretty(&metadata)?);
+        }
+        Err(_) => {
+            println!("Sidecar: none");
+            let (pid, exe) = parse_core_name(&file_name);
+            if let Some(pid) = pid {
+                println!("PID (from name): {}", pid);
+            }
+            if let Some(exe) = exe {
+                println!("Exe (from name): {}", exe);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extensions that mean the core isn't a plain ELF file.
+const ENCODINGS: &[(&str, &str)] = &[
+    (".gz", "gzip compressed"),
+    (".zst", "zstd compressed"),
+    (".xz", "xz compressed"),
+    (".age", "age encrypted"),
+    (".truncated", "truncated by set_max_size()"),
+];
+
+/// Strip any `ENCODINGS` extensions off the end of `name`, returning what's
+/// left and the descriptions of the ones removed (outermost first).
+pub fn strip_encodings(name: &str) -> (&str, Vec<&'static str>) {
+    let mut name = name;
+    let mut encodings = Vec::new();
+    while let Some((stripped, description)) = ENCODINGS
+        .iter()
+        .find_map(|(extension, description)| name.strip_suffix(extension).map(|stripped| (stripped, *description)))
+    {
+        name = stripped;
+        encodings.push(description);
+    }
+    (name, encodings)
+}
+
+/// Get the PID and exe from a name like `core.<pid>.<exe>`, as suggested in
+/// the README.
+pub fn parse_core_name(name: &str) -> (Option<u32>, Option<String>) {
+    let (name, _) = strip_encodings(name);
+    let rest = match name.strip_prefix("core.") {
+        Some(rest) => rest,
+        None => return (None, None),
+    };
+    match rest.split_once('.') {
+        Some((pid, exe)) => match pid.parse() {
+            Ok(pid) => (Some(pid), Some(exe.to_owned()).filter(|exe| !exe.is_empty())),
+            Err(_) => (None, Some(exe.to_owned())),
+        },
+        None => (rest.parse().ok(), None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_tag_key, parse_core_name, redact_cmdline, redact_env, strip_encodings, wrapper_context, REDACTED};
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    #[test]
+    fn test_parse_core_name() {
+        assert_eq!(parse_core_name("core.123.foo"), (Some(123), Some("foo".to_owned())));
+        assert_eq!(parse_core_name("core.123.foo.bar"), (Some(123), Some("foo.bar".to_owned())));
+        assert_eq!(parse_core_name("core.123.foo.zst"), (Some(123), Some("foo".to_owned())));
+        assert_eq!(parse_core_name("core.123"), (Some(123), None));
+        assert_eq!(parse_core_name("core.bob.foo"), (None, Some("foo".to_owned())));
+        assert_eq!(parse_core_name("something"), (None, None));
+    }
+
+    #[test]
+    fn test_strip_encodings() {
+        assert_eq!(strip_encodings("core.1.foo.zst.age"), ("core.1.foo", vec!["age encrypted", "zstd compressed"]));
+        assert_eq!(strip_encodings("core.1.agent"), ("core.1.agent", vec![]));
+    }
+
+    #[test]
+    fn test_check_tag_key() {
+        for good in ["service", "deploy_version", "k8s.namespace", "_x", "region-1"] {
+            assert!(check_tag_key(good).is_ok(), "{}", good);
+        }
+        for bad in ["", "1abc", "has space", "a/b", "caf\u{e9}", &"x".repeat(65)] {
+            assert!(check_tag_key(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let vars = strings(&["HOME=/home/a", "github_token=abc", "DB_PASS=x", "EMPTY"]);
+        let env = redact_env(&vars, &strings(&["db_*"]));
+        assert_eq!(env["HOME"], "/home/a");
+        assert_eq!(env["github_token"], REDACTED);
+        assert_eq!(env["DB_PASS"], REDACTED);
+        assert_eq!(env["EMPTY"], "");
+
+        let args = redact_cmdline(&strings(&["server", "--api-key=abc", "--port=80", "--token", "abc"]), &[]);
+        assert_eq!(args, strings(&["server", "--api-key=<redacted>", "--port=80", "--token", "abc"]));
+    }
+
+    #[test]
+    fn test_wrapper_context() {
+        let var = |name: &str, value: &str| (OsString::from(name), OsString::from(value));
+        let vars = vec![
+            var("SELLAFIELD_CTX_CLUSTER", "eu-1"),
+            var("SELLAFIELD_CTX_Region", "west"),
+            var("SELLAFIELD_CTX_", "no name"),
+            var("PATH", "/usr/bin"),
+            (OsString::from("SELLAFIELD_CTX_BINARY"), OsString::from_vec(vec![0xff])),
+        ];
+        let context = wrapper_context(vars.into_iter());
+        assert_eq!(context.len(), 2);
+        assert_eq!(context["cluster"], "eu-1");
+        assert_eq!(context["region"], "west");
+    }
+}
@@ -0,0 +1,90 @@
+//! `mount_point()`: which mount a path is on, from `/proc/self/mountinfo`.
+
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+
+/// The mount points listed in `mountinfo`. Each line is
+/// `id parent major:minor root mount_point options ...`, with spaces and
+/// other awkward characters in paths escaped as octal like `\040`.
+pub fn parse_mountinfo(mountinfo: &str) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mount_point| PathBuf::from(unescape(mount_point)))
+        .collect()
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let code = rest.get(i + 1..i + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match code {
+            Some(code) => {
+                out.push(code as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The most specific of `mounts` containing `path`, or `/` if none do.
+pub fn containing(mounts: &[PathBuf], path: &Path) -> PathBuf {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(mount))
+        .max_by_key(|mount| mount.components().count())
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
+
+/// The mount point of the filesystem containing `path`. Symlinks are resolved
+/// and the path doesn't have to exist yet; its closest existing ancestor is
+/// used instead.
+pub fn mount_point(path: &Path) -> PathBuf {
+    let resolved = path
+        .ancestors()
+        .find_map(|dir| dir.canonicalize().ok())
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let mounts = fs::read_to_string("/proc/self/mountinfo")
+        .map(|mountinfo| parse_mountinfo(&mountinfo))
+        .unwrap_or_default();
+    containing(&mounts, &resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{containing, mount_point, parse_mountinfo};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:21 / /proc rw,nosuid,nodev,noexec shared:12 - proc proc rw
+24 22 8:2 / /home rw,relatime shared:2 - ext4 /dev/sda2 rw
+25 24 8:3 / /home/my\\040disk rw,relatime shared:3 - ext4 /dev/sda3 rw
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts, ["/", "/proc", "/home", "/home/my disk"].map(PathBuf::from));
+
+        assert_eq!(containing(&mounts, Path::new("/home/tim/core")), Path::new("/home"));
+        assert_eq!(containing(&mounts, Path::new("/home/my disk/core")), Path::new("/home/my disk"));
+        // Whole components only.
+        assert_eq!(containing(&mounts, Path::new("/homer")), Path::new("/"));
+        assert_eq!(containing(&[], Path::new("/home")), Path::new("/"));
+    }
+
+    #[test]
+    fn test_mount_point() {
+        assert_eq!(mount_point(Path::new("/proc/self/status")), Path::new("/proc"));
+        assert_eq!(mount_point(Path::new("/proc/missing/file")), Path::new("/proc"));
+    }
+}
@@ -0,0 +1,79 @@
+//! Parsing and checking permission modes from the config script.
+
+/// Check a mode passed to `call()`, e.g. `set_permissions()`. The setuid and
+/// setgid bits are out: the kernel clears them when the crashing user writes
+/// the core, so they'd just silently disappear.
+pub fn check_mode(call: &str, mode: i64) -> Result<u64, String> {
+    if !(0..=0o1777).contains(&mode) {
+        let shown = if mode < 0 { mode.to_string() } else { format!("{:#o}", mode) };
+        return Err(format!(
+            "invalid permissions {} for {}(); must be between 0 and 0o1777 (setuid and setgid aren't kept)",
+            shown, call
+        ));
+    }
+    Ok(mode as u64)
+}
+
+/// Check a mask passed to `set_umask()`.
+pub fn check_umask(mask: i64) -> Result<u64, String> {
+    if !(0..=0o777).contains(&mask) {
+        return Err(format!("invalid umask {}; must be between 0 and 0o777", mask));
+    }
+    Ok(mask as u64)
+}
+
+/// Parse an octal (`"0600"`, `"0o600"`) or symbolic (`"rw-------"`) mode.
+pub fn parse_mode(s: &str) -> Result<u64, String> {
+    if s.len() == 9 && !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_symbolic(s);
+    }
+
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| ('0'..='7').contains(&c)) {
+        return Err(format!(
+            "invalid permissions '{}'; expected up to 4 octal digits (e.g. \"0640\") or a mode like \"rw-r-----\"",
+            s
+        ));
+    }
+    // Can't fail because of the checks above.
+    Ok(u64::from_str_radix(digits, 8).unwrap())
+}
+
+fn parse_symbolic(s: &str) -> Result<u64, String> {
+    let mut mode = 0;
+    for (i, c) in s.chars().enumerate() {
+        let bit = 1 << (8 - i);
+        let expected = ['r', 'w', 'x'][i % 3];
+        if c == expected {
+            mode |= bit;
+        } else if c != '-' {
+            return Err(format!(
+                "invalid permissions '{}'; character {} should be '{}' or '-'",
+                s,
+                i + 1,
+                expected
+            ));
+        }
+    }
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_mode;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0600"), Ok(0o600));
+        assert_eq!(parse_mode("640"), Ok(0o640));
+        assert_eq!(parse_mode("0o404"), Ok(0o404));
+        assert_eq!(parse_mode("4755"), Ok(0o4755));
+        assert_eq!(parse_mode("rw-------"), Ok(0o600));
+        assert_eq!(parse_mode("rwxr-x--x"), Ok(0o751));
+        assert_eq!(parse_mode("---------"), Ok(0));
+
+        for bad in ["", "0o", "0800", "12345", "rw", "rw-rw-rwz", "wr-------", "0x600"] {
+            assert!(parse_mode(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Details of the crashed process read from `/proc/<pid>`.
+//!
+//! The process stays around until we've finished reading the core from
+//! stdin, but it still belongs to its original user (and may not be
+//! dumpable), so this has to be read before we drop privileges.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufReader, ErrorKind},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessInfo {
+    /// Contents of `/proc/<pid>/status`.
+    pub status: Option<Vec<u8>>,
+    /// Contents of `/proc/<pid>/stat`.
+    pub stat: Option<Vec<u8>>,
+    /// Target of `/proc/<pid>/exe`, without any ` (deleted)` suffix. Like
+    /// `%E` this is the path in the process's mount namespace, so for a
+    /// process in a container it's only meaningful under `/proc/<pid>/root`.
+    pub exe: Option<PathBuf>,
+    /// Whether the executable has been deleted or replaced since the process
+    /// started, e.g. by a deploy. `false` if we can't tell.
+    pub exe_deleted: bool,
+    /// Contents of `/proc/<pid>/cgroup`.
+    pub cgroup: Option<Vec<u8>>,
+    /// Contents of `/proc/<pid>/environ`.
+    pub environ: Option<Vec<u8>>,
+    /// Contents of `/proc/<pid>/cmdline`.
+    pub cmdline: Option<Vec<u8>>,
+    /// GNU build ID of the executable. This is read through `/proc/<pid>/exe`
+    /// so it's right even if the file has since been replaced.
+    pub build_id: Option<Vec<u8>>,
+    /// Targets of the links in `/proc/<pid>/fd`, e.g. `/var/log/foo.log`,
+    /// `socket:[12345]` or `pipe:[12345]`, by fd number. Up to `MAX_FDS`.
+    pub fds: Option<BTreeMap<u32, String>>,
+    /// Seconds since boot from `/proc/uptime`, read with the rest so it
+    /// can be compared with the start time in `stat`.
+    pub system_uptime: Option<f64>,
+}
+
+/// Most fds recorded, so a leak of millions doesn't hold up the core.
+pub const MAX_FDS: usize = 65536;
+
+/// Where procfs is normally mounted.
+pub const PROC_ROOT: &str = "/proc";
+
+impl ProcessInfo {
+    /// Read whatever we can. Anything that's unavailable (e.g. the process
+    /// has already gone) is just left as `None`. `None` if `/proc` isn't
+    /// mounted at all, e.g. in a minimal container, so the caller can say so
+    /// once rather than every detail just quietly being missing.
+    pub fn read(pid: u32) -> Option<Self> {
+        Self::read_from(Path::new(PROC_ROOT), pid)
+    }
+
+    /// `read()` with procfs at `root`.
+    pub fn read_from(root: &Path, pid: u32) -> Option<Self> {
+        // /proc/self is always there if procfs is.
+        if root.join("self").symlink_metadata().is_err() {
+            return None;
+        }
+        let dir = root.join(pid.to_string());
+        let exe = std::fs::read_link(dir.join("exe")).ok();
+        Some(Self {
+            status: std::fs::read(dir.join("status")).ok(),
+            stat: std::fs::read(dir.join("stat")).ok(),
+            exe_deleted: exe.as_deref().is_some_and(|exe| exe_deleted(&dir, exe)),
+            exe: exe.map(strip_deleted),
+            cgroup: std::fs::read(dir.join("cgroup")).ok(),
+            environ: std::fs::read(dir.join("environ")).ok(),
+            cmdline: std::fs::read(dir.join("cmdline")).ok(),
+            build_id: read_build_id(&dir.join("exe")),
+            fds: read_fds(&dir.join("fd")),
+            system_uptime: std::fs::read_to_string(root.join("uptime"))
+                .ok()
+                .and_then(|uptime| uptime.split_whitespace().next()?.parse().ok()),
+        })
+    }
+}
+
+/// Fds that are closed while we're reading, or whose targets we can't read,
+/// are left out.
+fn read_fds(dir: &Path) -> Option<BTreeMap<u32, String>> {
+    let fds = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let target = std::fs::read_link(entry.path()).ok()?;
+            Some((fd, target.to_string_lossy().into_owned()))
+        })
+        .take(MAX_FDS)
+        .collect();
+    Some(fds)
+}
+
+impl ProcessInfo {
+    /// The effective UID from the `Uid:` line of `status`, which has the
+    /// real, effective, saved and filesystem UIDs.
+    pub fn effective_uid(&self) -> Option<u32> {
+        let status = String::from_utf8_lossy(self.status.as_ref()?);
+        let line = status.lines().find_map(|line| line.strip_prefix("Uid:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    /// How long the process had been running, from its start time in `stat`
+    /// (in clock ticks since boot).
+    pub fn uptime_secs(&self) -> Option<f64> {
+        let stat = String::from_utf8_lossy(self.stat.as_ref()?);
+        // The name in brackets can contain anything, including spaces and
+        // brackets, so count the fields from after the last `)`. starttime is
+        // field 22, and the state after the name is field 3.
+        let fields = &stat[stat.rfind(')')? + 1..];
+        let start_ticks: u64 = fields.split_whitespace().nth(22 - 3).and_then(|ticks| ticks.parse().ok())?;
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks_per_sec <= 0 {
+            return None;
+        }
+        Some((self.system_uptime? - start_ticks as f64 / ticks_per_sec as f64).max(0.0))
+    }
+
+    /// The process's `comm` from the `Name:` line of `status`.
+    pub fn comm(&self) -> Option<String> {
+        let status = String::from_utf8_lossy(self.status.as_ref()?);
+        let name = status.lines().find_map(|line| line.strip_prefix("Name:"))?;
+        Some(name.trim_start_matches('\t').to_owned())
+    }
+}
+
+/// Split the NUL-terminated strings of `environ` or `cmdline`.
+pub fn split_nul(data: &[u8]) -> Vec<String> {
+    data.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// For interpreters, the name of the script (or jar, Java class or Python
+/// module) they're running, from their arguments, e.g. `app.py` for
+/// `python3 -u /srv/app.py --port 80`. `None` for anything else, or if it
+/// was run with inline code (`python -c ...`) or can't be worked out.
+pub fn script_name(exe: &str, args: &[String]) -> Option<String> {
+    // Ignore versions, e.g. python3.11.
+    let interpreter = exe.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    // Options that take a value, as a separate argument, and options after
+    // which there's no script.
+    let (with_value, inline): (&[&str], &[&str]) = match interpreter {
+        "python" | "pypy" => (&["-W", "-X", "-Q"], &["-c"]),
+        "node" | "nodejs" => (&["-r", "--require", "--loader", "--import"], &["-e", "--eval", "-p", "--print"]),
+        "ruby" => (&["-I", "-r", "-C"], &["-e"]),
+        "perl" => (&["-I"], &["-e", "-E"]),
+        "php" => (&["-c", "-d"], &["-r"]),
+        "lua" | "luajit" => (&["-l"], &["-e"]),
+        "sh" | "bash" | "dash" | "zsh" | "ksh" => (&["-o", "-O"], &["-c"]),
+        "java" => (&["-cp", "-classpath", "--class-path", "-p", "--module-path"], &[]),
+        _ => return None,
+    };
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.as_str();
+        if inline.contains(&arg) {
+            return None;
+        }
+        // The script is whatever comes after these.
+        if arg == "-jar" || (interpreter.starts_with("py") && arg == "-m") || arg == "--module" {
+            return args.next().map(|name| file_name(name));
+        }
+        if with_value.contains(&arg) {
+            args.next();
+            continue;
+        }
+        if arg == "--" {
+            return args.next().map(|name| file_name(name));
+        }
+        if !arg.starts_with('-') {
+            return Some(file_name(arg));
+        }
+    }
+    None
+}
+
+fn file_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_owned()
+}
+
+fn read_build_id(path: &Path) -> Option<Vec<u8>> {
+    let mut file = BufReader::new(std::fs::File::open(path).ok()?);
+    crate::elf::build_id(&mut file).ok().flatten()
+}
+
+/// Whether `exe`, the target of `/proc/<pid>/exe` in `dir`, is gone. The
+/// kernel marks it if the file was deleted, but not if it was in a directory
+/// that has since gone (e.g. an old release directory), so check that too.
+/// The path is in the process's mount namespace, so look it up through
+/// `/proc/<pid>/root` and compare it with what `exe` really points to;
+/// otherwise everything in a container would look deleted.
+fn exe_deleted(dir: &Path, exe: &Path) -> bool {
+    if exe.as_os_str().as_bytes().ends_with(b" (deleted)") {
+        return true;
+    }
+    let running = match std::fs::metadata(dir.join("exe")) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    match std::fs::metadata(dir.join("root").join(exe.strip_prefix("/").unwrap_or(exe))) {
+        Ok(on_disk) => (on_disk.dev(), on_disk.ino()) != (running.dev(), running.ino()),
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    }
+}
+
+/// The kernel appends ` (deleted)` to links to files that have been deleted
+/// (e.g. the binary was upgraded while it was running).
+fn strip_deleted(path: PathBuf) -> PathBuf {
+    match path.as_os_str().as_bytes().strip_suffix(b" (deleted)") {
+        Some(stripped) => PathBuf::from(OsString::from_vec(stripped.to_vec())),
+        None => path,
+    }
+}
+
+/// Get the cgroup path from the contents of `/proc/<pid>/cgroup`. With
+/// cgroup v2 that's the `0::<path>` line. With v1 there's one line per
+/// hierarchy and they're usually all the same for containers, so we take the
+/// first one that isn't the root.
+pub fn cgroup_path(cgroup: &str) -> String {
+    let paths: Vec<(&str, &str)> = cgroup
+        .lines()
+        .filter_map(|line| {
+            let (id, rest) = line.split_once(':')?;
+            let (_controllers, path) = rest.split_once(':')?;
+            Some((id, path))
+        })
+        .collect();
+    if let Some((_, path)) = paths.iter().find(|(id, _)| *id == "0") {
+        return path.to_string();
+    }
+    paths
+        .iter()
+        .map(|(_, path)| *path)
+        .find(|path| *path != "/")
+        .or_else(|| paths.first().map(|(_, path)| *path))
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Find a container ID in a cgroup path. Docker, containerd, CRI-O and
+/// podman all put the 64 hex digit ID in the last part of the path, either
+/// on its own (`/docker/<id>`) or as a systemd scope
+/// (`/system.slice/docker-<id>.scope`). Empty if there isn't one, e.g.
+/// for processes that aren't in a container.
+pub fn container_id(cgroup_path: &str) -> String {
+    for part in cgroup_path.rsplit('/') {
+        let part = part.strip_suffix(".scope").unwrap_or(part);
+        let id = part.rsplit_once('-').map(|(_, id)| id).unwrap_or(part);
+        if id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return id.to_owned();
+        }
+    }
+    String::new()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cgroup_path, container_id, exe_deleted, script_name, strip_deleted, ProcessInfo};
+    use std::{
+        os::unix::io::AsRawFd,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn test_exe_deleted() {
+        // A fake /proc/<pid> for a process in a container, whose executable
+        // is /usr/bin/foo there but not on the host.
+        let dir = tempfile::tempdir().unwrap();
+        let container = dir.path().join("container");
+        let proc = dir.path().join("proc");
+        std::fs::create_dir_all(container.join("usr/bin")).unwrap();
+        std::fs::create_dir(&proc).unwrap();
+        std::os::unix::fs::symlink(&container, proc.join("root")).unwrap();
+        let foo = container.join("usr/bin/foo");
+        std::fs::write(&foo, "").unwrap();
+        std::os::unix::fs::symlink(&foo, proc.join("exe")).unwrap();
+        let exe = Path::new("/usr/bin/foo");
+        assert!(!exe_deleted(&proc, exe));
+        assert!(exe_deleted(&proc, Path::new("/usr/bin/foo (deleted)")));
+        // In a directory that's gone.
+        assert!(exe_deleted(&proc, Path::new("/release-1/foo")));
+
+        // Replaced by a different file.
+        let old = container.join("usr/bin/foo.old");
+        std::fs::rename(&foo, &old).unwrap();
+        std::fs::write(&foo, "").unwrap();
+        std::fs::remove_file(proc.join("exe")).unwrap();
+        std::os::unix::fs::symlink(&old, proc.join("exe")).unwrap();
+        assert!(exe_deleted(&proc, exe));
+
+        // Can't tell.
+        std::fs::remove_file(proc.join("exe")).unwrap();
+        assert!(!exe_deleted(&proc, Path::new("/release-1/foo")));
+    }
+
+    #[test]
+    fn test_strip_deleted() {
+        assert_eq!(strip_deleted(PathBuf::from("/usr/bin/foo (deleted)")), PathBuf::from("/usr/bin/foo"));
+        assert_eq!(strip_deleted(PathBuf::from("/usr/bin/foo")), PathBuf::from("/usr/bin/foo"));
+    }
+
+    #[test]
+    fn test_read_from() {
+        // Nothing mounted.
+        let root = tempfile::tempdir().unwrap();
+        assert!(ProcessInfo::read_from(root.path(), 42).is_none());
+
+        // Something like procfs, with only some of the files.
+        std::fs::create_dir(root.path().join("self")).unwrap();
+        std::fs::create_dir(root.path().join("42")).unwrap();
+        std::fs::write(root.path().join("42/cmdline"), "foo\0--bar\0").unwrap();
+        std::fs::write(root.path().join("uptime"), "1234.5 100.0\n").unwrap();
+        let info = ProcessInfo::read_from(root.path(), 42).unwrap();
+        assert_eq!(info.cmdline.as_deref(), Some(&b"foo\0--bar\0"[..]));
+        assert_eq!(info.system_uptime, Some(1234.5));
+        assert_eq!(info.exe, None);
+        assert_eq!(info.status, None);
+        assert!(!info.exe_deleted);
+        assert!(ProcessInfo::read_from(root.path(), 43).unwrap().cmdline.is_none());
+    }
+
+    #[test]
+    fn test_read_self() {
+        let info = ProcessInfo::read(std::process::id()).unwrap();
+        assert_eq!(info.exe, Some(std::env::current_exe().unwrap()));
+        assert!(info.status.is_some());
+        // The test binary is sellafield-<hash>, cut down to 15 characters.
+        assert_eq!(info.comm().unwrap().len(), 15);
+        assert!(info.comm().unwrap().starts_with("sellafield-"));
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let info = ProcessInfo::read(std::process::id()).unwrap();
+        let fds = info.fds.as_ref().unwrap();
+        let target = file.path().to_string_lossy();
+        assert_eq!(fds.get(&(file.as_file().as_raw_fd() as u32)).map(String::as_str), Some(&*target));
+        assert!(ProcessInfo::read(u32::MAX).unwrap().fds.is_none());
+        assert!(!info.exe_deleted);
+        assert!(info.uptime_secs().is_some_and(|secs| (0.0..3600.0).contains(&secs)));
+        assert_eq!(ProcessInfo::read(u32::MAX).unwrap().uptime_secs(), None);
+
+        let stat = |stat: &str| ProcessInfo {
+            stat: Some(stat.as_bytes().to_vec()),
+            system_uptime: Some(1000.0),
+            ..Default::default()
+        };
+        let fields = "S 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 50000 20 21";
+        let secs = stat(&format!("42 (a) b) {}", fields)).uptime_secs().unwrap();
+        assert_eq!(secs, 1000.0 - 50000.0 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64);
+        assert_eq!(stat("42 (foo) S 1 2").uptime_secs(), None);
+    }
+
+    #[test]
+    fn test_script_name() {
+        let name = |exe: &str, args: &str| {
+            let args: Vec<String> = args.split(' ').map(String::from).collect();
+            script_name(exe, &args)
+        };
+        assert_eq!(name("python3.11", "python3 -u -W ignore /srv/app.py --port 80").as_deref(), Some("app.py"));
+        assert_eq!(name("python3", "python3 -m http.server 8000").as_deref(), Some("http.server"));
+        assert_eq!(name("python3", "python3 -c print(1)"), None);
+        assert_eq!(name("python3", "python3"), None);
+        assert_eq!(name("node", "node --require ./tracing.js dist/server.js").as_deref(), Some("server.js"));
+        assert_eq!(name("java", "java -Xmx4g -cp lib/* -jar /opt/svc.jar").as_deref(), Some("svc.jar"));
+        assert_eq!(name("java", "java -cp lib/* com.example.Main").as_deref(), Some("com.example.Main"));
+        assert_eq!(name("bash", "bash -- ./-weird.sh").as_deref(), Some("-weird.sh"));
+        assert_eq!(name("foo", "foo bar.py"), None);
+    }
+
+    #[test]
+    fn test_cgroup_path() {
+        assert_eq!(cgroup_path("0::/user.slice/user-1000.slice/session-2.scope\n"), "/user.slice/user-1000.slice/session-2.scope");
+        let v1 = "12:pids:/docker/abc\n11:cpu,cpuacct:/docker/abc\n1:name=systemd:/docker/abc\n";
+        assert_eq!(cgroup_path(v1), "/docker/abc");
+        assert_eq!(cgroup_path("2:cpu:/\n1:name=systemd:/init.scope\n"), "/init.scope");
+        assert_eq!(cgroup_path("1:cpu:/\n"), "/");
+        assert_eq!(cgroup_path(""), "");
+    }
+
+    #[test]
+    fn test_container_id() {
+        let id = "4f1e2c7d3b09a8e6f5c4d3b2a1908f7e6d5c4b3a29180f7e6d5c4b3a2918a7b6";
+        assert_eq!(container_id(&format!("/docker/{}", id)), id);
+        assert_eq!(container_id(&format!("/system.slice/docker-{}.scope", id)), id);
+        assert_eq!(
+            container_id(&format!(
+                "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1234_5678.slice/cri-containerd-{}.scope",
+                id
+            )),
+            id
+        );
+        assert_eq!(container_id(&format!("/kubepods/besteffort/pod1234-5678/{}", id)), id);
+        assert_eq!(container_id("/user.slice/user-1000.slice/session-2.scope"), "");
+        assert_eq!(container_id(""), "");
+    }
+
+    #[test]
+    fn test_effective_uid() {
+        let info = ProcessInfo {
+            status: Some(b"Name:\tpasswd\nUid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\t1000\n".to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(info.effective_uid(), Some(0));
+        assert_eq!(ProcessInfo::default().effective_uid(), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! `sellafield reassemble`: put a core split by `set_split_bytes()` back
+//! together.
+
+use anyhow::{bail, Context, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{chunk_path, metadata::Metadata, with_suffix};
+
+#[derive(FromArgs)]
+/// Join the `<core>.000`, `<core>.001`... chunks of a split core, checking
+/// none are missing.
+#[argh(subcommand, name = "reassemble")]
+pub struct ReassembleOpts {
+    /// path of the core without the chunk number.
+    #[argh(positional)]
+    core: PathBuf,
+
+    /// file to write the core to. Defaults to stdout, e.g. for piping into
+    /// gdb.
+    #[argh(option)]
+    output: Option<PathBuf>,
+}
+
+pub fn reassemble(opts: &ReassembleOpts) -> Result<()> {
+    let chunks = chunks(&opts.core)?;
+    match &opts.output {
+        Some(output) => {
+            let mut file = fs::File::create(output)?;
+            copy_chunks(&chunks, &mut file)?;
+            file.sync_all()?;
+        }
+        None => copy_chunks(&chunks, &mut io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+/// The chunks of `core`. If it has a sidecar that says how many there should
+/// be; otherwise it's everything up to the first gap, and a gap followed by
+/// more chunks is an error.
+pub fn chunks(core: &Path) -> Result<Vec<PathBuf>> {
+    let sidecar = with_suffix(core, ".json");
+    let expected = match fs::read(&sidecar) {
+        Ok(json) => {
+            let metadata: Metadata =
+                serde_json::from_slice(&json).with_context(|| format!("error parsing {}", sidecar.display()))?;
+            match metadata.chunks {
+                Some(chunks) => Some(chunks),
+                None => bail!("{} says {} isn't split", sidecar.display(), core.display()),
+            }
+        }
+        Err(_) => None,
+    };
+
+    let mut chunks = Vec::new();
+    loop {
+        let chunk = chunk_path(core, chunks.len() as u32);
+        if !chunk.exists() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+    if chunks.is_empty() {
+        bail!("no chunks found for {}; expected {}", core.display(), chunk_path(core, 0).display());
+    }
+
+    let found = chunks.len() as u32;
+    match expected {
+        Some(expected) if found < expected => {
+            bail!("{} is missing; the sidecar says there are {} chunks", chunk_path(core, found).display(), expected)
+        }
+        Some(expected) if found > expected => {
+            bail!("found {} chunks but the sidecar says there should be {}", found, expected)
+        }
+        // Look a little further for a gap. Without a sidecar that's the only
+        // way to tell one's missing.
+        None => {
+            if let Some(after) = (found + 1..found + 10).map(|i| chunk_path(core, i)).find(|chunk| chunk.exists()) {
+                bail!("{} is missing but {} exists", chunk_path(core, found).display(), after.display());
+            }
+        }
+        _ => {}
+    }
+    Ok(chunks)
+}
+
+fn copy_chunks(chunks: &[PathBuf], out: &mut impl Write) -> Result<()> {
+    for chunk in chunks {
+        io::copy(&mut fs::File::open(chunk)?, out).with_context(|| format!("error copying {}", chunk.display()))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reassemble, ReassembleOpts};
+    use crate::metadata::Metadata;
+
+    #[test]
+    fn test_reassemble() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = dir.path().join("core");
+        for (i, chunk) in ["0123", "4567", "89"].iter().enumerate() {
+            std::fs::write(dir.path().join(format!("core.{:03}", i)), chunk).unwrap();
+        }
+        let output = dir.path().join("joined");
+        let opts = ReassembleOpts {
+            core: core.clone(),
+            output: Some(output.clone()),
+        };
+        reassemble(&opts).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "0123456789");
+
+        // With a sidecar the count has to match.
+        let metadata = Metadata {
+            chunks: Some(4),
+            ..Default::default()
+        };
+        std::fs::write(dir.path().join("core.json"), serde_json::to_vec(&metadata).unwrap()).unwrap();
+        let error = reassemble(&opts).unwrap_err().to_string();
+        assert!(error.contains("core.003 is missing"), "{}", error);
+
+        // Without one gaps are still noticed.
+        std::fs::remove_file(dir.path().join("core.json")).unwrap();
+        std::fs::remove_file(dir.path().join("core.001")).unwrap();
+        let error = reassemble(&opts).unwrap_err().to_string();
+        assert!(error.contains("core.001 is missing but"), "{}", error);
+    }
+}
@@ -0,0 +1,380 @@
+//! Deleting old cores according to a retention policy.
+//!
+//! Only cores with a sidecar (see `set_save_metadata()`) are considered,
+//! so we never delete anything we can't prove we wrote.
+
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use fs_err as fs;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{chunk_path, metadata::Metadata, run_policy_script, with_suffix, DEFAULT_CONFIG};
+
+/// Files we may write next to a core.
+pub const SIDECAR_SUFFIXES: &[&str] = &[".json", ".status", ".stat", ".head"];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Policy {
+    /// Delete cores that crashed more than this many days ago.
+    pub max_age_days: Option<u64>,
+    /// Delete the oldest cores until the total size is below this.
+    pub max_total_bytes: Option<u64>,
+    /// Keep at most this many cores for each exe.
+    pub max_per_exe: Option<u64>,
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        self.max_age_days.is_none() && self.max_total_bytes.is_none() && self.max_per_exe.is_none()
+    }
+}
+
+#[derive(FromArgs)]
+/// Delete old cores from a directory according to a retention policy.
+#[argh(subcommand, name = "prune")]
+pub struct PruneOpts {
+    /// directory to prune. Subdirectories are included.
+    #[argh(option)]
+    dir: PathBuf,
+
+    /// delete cores that crashed more than this many days ago.
+    #[argh(option)]
+    max_age_days: Option<u64>,
+
+    /// delete the oldest cores until the total size is below this.
+    #[argh(option)]
+    max_total_bytes: Option<u64>,
+
+    /// keep at most this many cores for each executable.
+    #[argh(option)]
+    max_per_exe: Option<u64>,
+
+    /// list what would be deleted without deleting anything.
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+pub fn prune(opts: &PruneOpts) -> Result<()> {
+    let policy = Policy {
+        max_age_days: opts.max_age_days,
+        max_total_bytes: opts.max_total_bytes,
+        max_per_exe: opts.max_per_exe,
+    };
+    for core in apply(&opts.dir, &policy, now(), opts.dry_run)? {
+        if opts.dry_run {
+            println!("Would delete {}", core.display());
+        } else {
+            println!("Deleted {}", core.display());
+        }
+    }
+    Ok(())
+}
+
+#[derive(FromArgs)]
+/// Delete old cores according to the retention policy in a config script
+/// (the `set_max_*()` functions).
+#[argh(subcommand, name = "gc")]
+pub struct GcOpts {
+    /// config script to read the retention policy from. Defaults to
+    /// /etc/sellafield.rhai.
+    #[argh(option, default = "PathBuf::from(DEFAULT_CONFIG)")]
+    config: PathBuf,
+
+    /// directory to apply the policy to. Defaults to the directory from the
+    /// config script.
+    #[argh(option)]
+    dir: Option<PathBuf>,
+
+    /// list what would be deleted without deleting anything.
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+pub fn gc(opts: &GcOpts) -> Result<()> {
+    let config = run_policy_script(&opts.config)?;
+    let dir = match opts.dir.clone().or_else(|| config.retention_dir()) {
+        Some(dir) => dir,
+        None => bail!("no directory given and the config script doesn't set one"),
+    };
+    if config.retention.is_empty() {
+        bail!("the config script doesn't set a retention policy");
+    }
+    for core in apply(&dir, &config.retention, now(), opts.dry_run)? {
+        if opts.dry_run {
+            println!("Would delete {}", core.display());
+        } else {
+            println!("Deleted {}", core.display());
+        }
+    }
+    Ok(())
+}
+
+/// A core we found with its sidecar.
+#[derive(Debug)]
+pub struct StoredCore {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    /// Size of the core and all its sidecar files.
+    pub bytes: u64,
+}
+
+impl StoredCore {
+    /// The core (or its chunks) and whichever sidecar files exist.
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files = match self.metadata.chunks {
+            Some(chunks) => (0..chunks).map(|i| chunk_path(&self.path, i)).collect(),
+            None => vec![self.path.clone()],
+        };
+        for suffix in SIDECAR_SUFFIXES {
+            let path = with_suffix(&self.path, suffix);
+            if path.symlink_metadata().is_ok() {
+                files.push(path);
+            }
+        }
+        files
+    }
+}
+
+/// Find all cores under `dir` that have a valid sidecar, oldest first.
+pub fn find_cores(dir: &Path) -> Result<Vec<StoredCore>> {
+    let mut cores = Vec::new();
+    find_cores_in(dir, &mut cores)?;
+    cores.sort_by_key(|core| (core.metadata.time, core.path.clone()));
+    Ok(cores)
+}
+
+fn find_cores_in(dir: &Path, cores: &mut Vec<StoredCore>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        // Don't follow symlinks, we don't want to wander off somewhere else.
+        if file_type.is_dir() {
+            find_cores_in(&path, cores)?;
+            continue;
+        }
+        if !file_type.is_file() || !path.to_string_lossy().ends_with(".json") {
+            continue;
+        }
+
+        let core_path = path.with_extension("");
+        // Ignore JSON files that aren't our sidecars.
+        let metadata: Metadata = match fs::read(&path).ok().and_then(|json| serde_json::from_slice(&json).ok()) {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+        // A split core is there if its first chunk is.
+        let first = match metadata.chunks {
+            Some(_) => chunk_path(&core_path, 0),
+            None => core_path.clone(),
+        };
+        if !first.symlink_metadata().is_ok_and(|meta| meta.is_file()) {
+            continue;
+        }
+
+        let mut core = StoredCore {
+            path: core_path,
+            metadata,
+            bytes: 0,
+        };
+        for file in core.files() {
+            core.bytes += file.symlink_metadata().map_or(0, |meta| meta.len());
+        }
+        cores.push(core);
+    }
+    Ok(())
+}
+
+/// Apply `policy` to the cores under `dir`. `now` is the current Unix time
+/// in seconds. Returns the cores that were deleted (or would have been if
+/// `dry_run` is set).
+pub fn apply(dir: &Path, policy: &Policy, now: u64, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let cores = find_cores(dir)?;
+    let evict = select_evictions(&cores, policy, now);
+
+    if dry_run {
+        return Ok(cores.iter().zip(&evict).filter(|(_, &evict)| evict).map(|(core, _)| core.path.clone()).collect());
+    }
+    delete(&cores, &evict)
+}
+
+/// Delete `uid`'s oldest cores under `dir` until they add up to no more than
+/// `quota` bytes. Other users' cores aren't counted or touched.
+pub fn apply_user_quota(dir: &Path, uid: u32, quota: u64) -> Result<Vec<PathBuf>> {
+    let cores: Vec<_> = find_cores(dir)?.into_iter().filter(|core| core.metadata.uid == uid).collect();
+    let policy = Policy {
+        max_total_bytes: Some(quota),
+        ..Default::default()
+    };
+    delete(&cores, &select_evictions(&cores, &policy, now()))
+}
+
+/// Cores we aren't allowed to delete (e.g. another user's, when we're
+/// running as the crashed process's user) are skipped with a warning.
+fn delete(cores: &[StoredCore], evict: &[bool]) -> Result<Vec<PathBuf>> {
+    let mut deleted = Vec::new();
+    'cores: for (core, _) in cores.iter().zip(evict).filter(|(_, &evict)| evict) {
+        for file in core.files() {
+            match fs::remove_file(file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    crate::warn(&format!("not deleting {}: {}", core.path.display(), e));
+                    continue 'cores;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        deleted.push(core.path.clone());
+    }
+    Ok(deleted)
+}
+
+/// Decide which of `cores` (oldest first) to delete.
+fn select_evictions(cores: &[StoredCore], policy: &Policy, now: u64) -> Vec<bool> {
+    let mut evict = vec![false; cores.len()];
+
+    if let Some(max_age_days) = policy.max_age_days {
+        let cutoff = now.saturating_sub(max_age_days * 24 * 60 * 60);
+        for (core, evict) in cores.iter().zip(evict.iter_mut()) {
+            if (core.metadata.time as u64) < cutoff {
+                *evict = true;
+            }
+        }
+    }
+
+    if let Some(max_per_exe) = policy.max_per_exe {
+        // Go newest first so we keep the newest ones.
+        let mut counts = HashMap::<&str, u64>::new();
+        for (core, evict) in cores.iter().zip(evict.iter_mut()).rev() {
+            if *evict {
+                continue;
+            }
+            let count = counts.entry(&core.metadata.exe).or_default();
+            *count += 1;
+            if *count > max_per_exe {
+                *evict = true;
+            }
+        }
+    }
+
+    if let Some(max_total_bytes) = policy.max_total_bytes {
+        let mut total: u64 = cores.iter().zip(&evict).filter(|(_, &evict)| !evict).map(|(core, _)| core.bytes).sum();
+        for (core, evict) in cores.iter().zip(evict.iter_mut()) {
+            if total <= max_total_bytes {
+                break;
+            }
+            if !*evict {
+                *evict = true;
+                total -= core.bytes;
+            }
+        }
+    }
+
+    evict
+}
+
+pub fn now() -> u64 {
+    (crate::timestamp() / 1000) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply, apply_user_quota, Policy};
+    use crate::metadata::Metadata;
+    use std::path::Path;
+
+    fn write_core(dir: &Path, name: &str, exe: &str, time: u32, bytes: usize) {
+        write_user_core(dir, name, 0, exe, time, bytes);
+    }
+
+    fn write_user_core(dir: &Path, name: &str, uid: u32, exe: &str, time: u32, bytes: usize) {
+        std::fs::write(dir.join(name), vec![0u8; bytes]).unwrap();
+        let metadata = Metadata {
+            uid,
+            exe: exe.to_owned(),
+            time,
+            ..Default::default()
+        };
+        std::fs::write(dir.join(format!("{}.json", name)), serde_json::to_vec(&metadata).unwrap()).unwrap();
+    }
+
+    fn names(deleted: Vec<std::path::PathBuf>) -> Vec<String> {
+        deleted.iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn test_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        write_core(dir.path(), "core.1", "foo", 1000, 10);
+        write_core(dir.path(), "core.2", "foo", 1000 + 3 * 86400, 10);
+        let policy = Policy {
+            max_age_days: Some(2),
+            ..Default::default()
+        };
+        let deleted = apply(dir.path(), &policy, 1000 + 4 * 86400, false).unwrap();
+        assert_eq!(names(deleted), ["core.1"]);
+        assert!(!dir.path().join("core.1").exists());
+        assert!(!dir.path().join("core.1.json").exists());
+        assert!(dir.path().join("core.2").exists());
+    }
+
+    #[test]
+    fn test_max_per_exe() {
+        let dir = tempfile::tempdir().unwrap();
+        write_core(dir.path(), "core.1", "foo", 1, 10);
+        write_core(dir.path(), "core.2", "bar", 2, 10);
+        write_core(dir.path(), "core.3", "foo", 3, 10);
+        write_core(dir.path(), "core.4", "foo", 4, 10);
+        let policy = Policy {
+            max_per_exe: Some(2),
+            ..Default::default()
+        };
+        let deleted = apply(dir.path(), &policy, 10, false).unwrap();
+        assert_eq!(names(deleted), ["core.1"]);
+    }
+
+    #[test]
+    fn test_max_total_bytes_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        write_core(dir.path(), "core.1", "foo", 1, 1000);
+        write_core(dir.path(), "core.2", "foo", 2, 1000);
+        write_core(dir.path(), "core.3", "foo", 3, 1000);
+        // A file without a sidecar is never touched.
+        std::fs::write(dir.path().join("precious"), vec![0u8; 10000]).unwrap();
+        let policy = Policy {
+            max_total_bytes: Some(2500),
+            ..Default::default()
+        };
+        let deleted = apply(dir.path(), &policy, 10, true).unwrap();
+        assert_eq!(names(deleted), ["core.1"]);
+        assert!(dir.path().join("core.1").exists());
+    }
+
+    #[test]
+    fn test_user_quota() {
+        let alice = tempfile::tempdir().unwrap();
+        let bob = tempfile::tempdir().unwrap();
+        for (dir, uid) in [(&alice, 1000), (&bob, 1001)] {
+            for time in 1..=3 {
+                write_user_core(dir.path(), &format!("core.{}", time), uid, "foo", time, 1000);
+            }
+        }
+        let deleted = apply_user_quota(alice.path(), 1000, 2500).unwrap();
+        assert_eq!(names(deleted), ["core.1"]);
+        assert!(!alice.path().join("core.1").exists());
+        assert!(bob.path().join("core.1").exists());
+
+        // In a shared directory other users' cores don't count.
+        let shared = tempfile::tempdir().unwrap();
+        write_user_core(shared.path(), "core.1", 1001, "foo", 1, 5000);
+        write_user_core(shared.path(), "core.2", 1000, "foo", 2, 1000);
+        write_user_core(shared.path(), "core.3", 1000, "foo", 3, 1000);
+        let deleted = apply_user_quota(shared.path(), 1000, 1500).unwrap();
+        assert_eq!(names(deleted), ["core.2"]);
+        assert!(shared.path().join("core.1").exists());
+    }
+}
@@ -0,0 +1,58 @@
+//! `set_keep_first()` and `set_sample_rate()`: keep the first few cores from
+//! each executable, then only one in every N after that.
+//!
+//! The number of crashes seen for each executable is kept in a small JSON file
+//! in the lock directory. Unlike the debounce state this has to be exact, so
+//! it's updated under an `flock()` of the file.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use crate::state::StateFile;
+
+pub const STATE_FILE: &str = "samples.json";
+
+/// Whether to keep crash number `count` (counting from 0) of an executable.
+pub fn keep(count: u64, keep_first: u64, sample_rate: u64) -> bool {
+    if count < keep_first {
+        return true;
+    }
+    sample_rate != 0 && (count - keep_first + 1).is_multiple_of(sample_rate)
+}
+
+/// Count another crash of `key` and return how many there were before it.
+/// Corrupt state just starts the counts again.
+pub fn count(state: &mut StateFile, key: &str) -> Result<u64> {
+    state.update(|counts: &mut BTreeMap<String, u64>| {
+        let count = counts.entry(key.to_owned()).or_default();
+        let before = *count;
+        *count += 1;
+        before
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count, keep, STATE_FILE};
+    use crate::state::StateFile;
+
+    #[test]
+    fn test_keep() {
+        let kept = |keep_first, sample_rate| (0..10).filter(|&i| keep(i, keep_first, sample_rate)).collect::<Vec<_>>();
+        assert_eq!(kept(3, 2), vec![0, 1, 2, 4, 6, 8]);
+        assert_eq!(kept(3, 0), vec![0, 1, 2]);
+        assert_eq!(kept(0, 4), vec![3, 7]);
+        assert_eq!(kept(2, 1), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = StateFile::open(dir.path(), STATE_FILE).unwrap();
+        assert_eq!(count(&mut state, "/usr/bin/foo").unwrap(), 0);
+        assert_eq!(count(&mut state, "/usr/bin/foo").unwrap(), 1);
+        assert_eq!(count(&mut state, "/usr/bin/bar").unwrap(), 0);
+        let mut reopened = StateFile::open(dir.path(), STATE_FILE).unwrap();
+        assert_eq!(count(&mut reopened, "/usr/bin/foo").unwrap(), 2);
+    }
+}
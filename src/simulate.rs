@@ -0,0 +1,176 @@
+//! `sellafield simulate`: run a crash through the normal argument parsing and
+//! config script, from a `core_pattern` and the values the kernel put in it,
+//! to see where its core would go.
+
+use anyhow::{anyhow, bail, Result};
+use argh::FromArgs;
+use std::path::PathBuf;
+
+use crate::{
+    decode_exe, exe_name, get_user_details, handle_crash, process::ProcessInfo, run_script, try_from_args,
+    unpack_args, Opts,
+};
+
+#[derive(FromArgs)]
+/// Run the config for a crash given as core pattern specifiers and their
+/// values, e.g. copied from a log, and report where the core would go.
+#[argh(subcommand, name = "simulate")]
+pub struct SimulateOpts {
+    /// the core pattern, e.g. '|/usr/bin/sellafield -u %u -p %p ...', or
+    /// just the specifiers, e.g. '%u %p %s %t %E %c'.
+    #[argh(option)]
+    pattern: String,
+
+    /// the values the kernel substituted for the specifiers, separated by
+    /// spaces and in the same order.
+    #[argh(option)]
+    values: String,
+
+    /// config script to run. Defaults to the one in the pattern, or
+    /// /etc/sellafield.rhai.
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// file to use as the core. Needed with --write.
+    #[argh(option)]
+    input: Option<PathBuf>,
+
+    /// really write the core, exactly as if the kernel had run sellafield,
+    /// rather than just showing where it would go.
+    #[argh(switch)]
+    write: bool,
+}
+
+/// The option each specifier is passed with, for patterns that are just the
+/// specifiers.
+const OPTIONS: &[(char, &str)] = &[
+    ('u', "-u"),
+    ('p', "-p"),
+    ('P', "-p"),
+    ('t', "-t"),
+    ('E', "-E"),
+    ('c', "-c"),
+    ('d', "-d"),
+    ('s', "-s"),
+    ('f', "-f"),
+    ('e', "-e"),
+];
+
+pub fn simulate(opts: &SimulateOpts) -> Result<()> {
+    let mut args = vec!["sellafield".to_owned()];
+    args.extend(pattern_args(&opts.pattern, &opts.values)?);
+    if let Some(config) = &opts.config {
+        args.push("--config".to_owned());
+        args.push(config.to_string_lossy().into_owned());
+    }
+    match &opts.input {
+        Some(input) => {
+            args.push("--input".to_owned());
+            args.push(input.to_string_lossy().into_owned());
+        }
+        None if opts.write => bail!("--write needs an --input core"),
+        None => {}
+    }
+    println!("sellafield would be run as: {}", args[1..].join(" "));
+
+    let args = unpack_args(args)?;
+    let crash: Opts = try_from_args(&args).map_err(|e| anyhow!("{}", e.output))?;
+
+    if opts.write {
+        let outcome = handle_crash(&crash)?;
+        println!("{:?} (exit code {})", outcome, outcome.exit_code());
+        return Ok(());
+    }
+
+    let user_details = get_user_details(crash.uid)?;
+    let full_exe = decode_exe(&crash.exe, crash.exe_encoding);
+    // The process is long gone, so there's nothing to read from /proc.
+    let mut config = run_script(&crash, &full_exe, &exe_name(&full_exe), &user_details, &ProcessInfo::default())?;
+    config.resolve_relative(crash.base_dir.as_deref().unwrap_or(&user_details.home));
+    println!("config: {}", serde_json::to_string_pretty(&config)?);
+    let outputs = config.outputs();
+    if outputs.is_empty() {
+        println!("no outputs; the core would be skipped");
+    }
+    for output in outputs {
+        println!("core would be written to {} with mode {:#o}", output.path.display(), output.permissions);
+    }
+    Ok(())
+}
+
+/// Expand the specifiers in `pattern` with `values`, giving sellafield's
+/// arguments. Like the kernel (since 5.3) the pattern is split into arguments
+/// before it's expanded. A pattern that's just specifiers gets the options
+/// they go with.
+fn pattern_args(pattern: &str, values: &str) -> Result<Vec<String>> {
+    let mut tokens: Vec<&str> = pattern.split_whitespace().collect();
+    // The program itself.
+    if tokens.first().is_some_and(|token| token.starts_with('|')) {
+        tokens.remove(0);
+    }
+    let mut values = values.split_whitespace();
+    let mut next_value = |specifier: char| {
+        values.next().ok_or_else(|| anyhow!("not enough --values; nothing for %{}", specifier))
+    };
+
+    let bare = !tokens.iter().any(|token| token.starts_with('-'));
+    let mut args = Vec::new();
+    for token in tokens {
+        let mut arg = String::new();
+        let mut chars = token.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                arg.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => arg.push('%'),
+                Some(specifier) => {
+                    let value = next_value(specifier)?;
+                    if bare {
+                        match OPTIONS.iter().find(|(s, _)| *s == specifier) {
+                            Some((_, option)) => args.push(option.to_string()),
+                            None => {
+                                println!("ignoring %{} ({}); sellafield doesn't use it", specifier, value);
+                                continue;
+                            }
+                        }
+                    }
+                    arg.push_str(value);
+                }
+                None => bail!("pattern ends with a lone %"),
+            }
+        }
+        if !arg.is_empty() {
+            args.push(arg);
+        }
+    }
+    if let Some(extra) = values.next() {
+        bail!("too many --values; nothing in the pattern for '{}'", extra);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::pattern_args;
+
+    #[test]
+    fn test_pattern_args() {
+        let values = "1000 1000 42 11 1700000000 !usr!bin!foo 1048576";
+        assert_eq!(
+            pattern_args("%u %g %p %s %t %E %c", values).unwrap(),
+            ["-u", "1000", "-p", "42", "-s", "11", "-t", "1700000000", "-E", "!usr!bin!foo", "-c", "1048576"]
+        );
+
+        let pattern = "|/usr/bin/sellafield -P %u:%p:%t:%c:%d:%E -s %s --config /etc/foo.rhai";
+        assert_eq!(
+            pattern_args(pattern, "1000 42 1700000000 0 1 !usr!bin!foo 6").unwrap(),
+            ["-P", "1000:42:1700000000:0:1:!usr!bin!foo", "-s", "6", "--config", "/etc/foo.rhai"]
+        );
+        assert_eq!(pattern_args("-x 100%%", "").unwrap(), ["-x", "100%"]);
+
+        assert!(pattern_args("%u %p", "1000").is_err());
+        assert!(pattern_args("%u", "1000 42").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! A system-wide limit on how many sellafields run at once, so a crash storm
+//! doesn't make things worse by saturating the disk.
+//!
+//! There are `max` slot files in the lock directory and each run holds an
+//! exclusive `flock()` on one of them until it exits. The kernel drops the
+//! lock when the process dies so there's nothing to clean up.
+
+use anyhow::{Context, Result};
+use fs_err as fs;
+use fs_err::os::unix::fs::OpenOptionsExt;
+use std::{
+    os::unix::{fs::DirBuilderExt, io::AsRawFd},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// What to do when all the slots are taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overload {
+    /// Drain and discard the core.
+    #[default]
+    Skip,
+    /// Wait up to `WAIT_LIMIT` for a slot, then skip.
+    Wait,
+}
+
+impl FromStr for Overload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Overload::Skip),
+            "wait" => Ok(Overload::Wait),
+            _ => Err(format!("invalid overload mode '{}'; expected 'wait' or 'skip'", s)),
+        }
+    }
+}
+
+pub const WAIT_LIMIT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A held slot. It's released when this is dropped.
+#[derive(Debug)]
+pub struct Slot {
+    _file: fs::File,
+}
+
+/// Get one of the `max` slots in `dir`, or `None` if they're all taken (for
+/// `WAIT_LIMIT` in `Wait` mode).
+pub fn acquire(dir: &Path, max: usize, overload: Overload) -> Result<Option<Slot>> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(dir)
+        .with_context(|| format!("error creating {}", dir.display()))?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(slot) = try_acquire(dir, max)? {
+            return Ok(Some(slot));
+        }
+        if overload == Overload::Skip || start.elapsed() >= WAIT_LIMIT {
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn try_acquire(dir: &Path, max: usize) -> Result<Option<Slot>> {
+    for i in 0..max {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(dir.join(format!("slot.{}", i)))?;
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if rc == 0 {
+            return Ok(Some(Slot { _file: file }));
+        }
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(error).with_context(|| format!("error locking {}", file.path().display()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{acquire, Overload};
+
+    #[test]
+    fn test_acquire() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = acquire(dir.path(), 2, Overload::Skip).unwrap();
+        let second = acquire(dir.path(), 2, Overload::Skip).unwrap();
+        assert!(first.is_some() && second.is_some());
+        // flock() locks belong to the open file so this conflicts even within
+        // one process.
+        assert!(acquire(dir.path(), 2, Overload::Skip).unwrap().is_none());
+
+        drop(first);
+        assert!(acquire(dir.path(), 2, Overload::Skip).unwrap().is_some());
+    }
+}
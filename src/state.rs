@@ -0,0 +1,122 @@
+//! Small JSON files of state shared by every sellafield, like the sampling
+//! counts, crash storm history and debounce times.
+//!
+//! They live in the lock directory, which belongs to root, and are opened
+//! before dropping privileges like the `--max-total-dumps` counter. In the
+//! output directory they would belong to whoever crashed first, so nobody
+//! else could update them and that user could hold the lock forever.
+//! Locking gives up after `LOCK_WAIT` for the same reason.
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use fs_err::os::unix::fs::OpenOptionsExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    io::{Read, Seek, Write},
+    os::unix::{fs::DirBuilderExt, io::AsRawFd},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Longest to wait for another sellafield to finish updating a file.
+/// They only hold it for a read and a write.
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An open state file.
+#[derive(Debug)]
+pub struct StateFile {
+    file: fs::File,
+}
+
+impl StateFile {
+    pub fn open(dir: &Path, name: &str) -> Result<Self> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(dir)
+            .with_context(|| format!("error creating {}", dir.display()))?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(dir.join(name))?;
+        Ok(Self { file })
+    }
+
+    /// Run `f` on the state with the file locked, and save it afterwards.
+    /// Missing or corrupt state is the default.
+    pub fn update<T, R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Result<R>
+    where
+        T: Default + Serialize + DeserializeOwned,
+    {
+        self.lock()?;
+        let result = self.update_locked(f);
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        result
+    }
+
+    fn update_locked<T, R>(&mut self, f: impl FnOnce(&mut T) -> R) -> Result<R>
+    where
+        T: Default + Serialize + DeserializeOwned,
+    {
+        let mut json = Vec::new();
+        self.file.rewind()?;
+        self.file.read_to_end(&mut json)?;
+        let mut state = serde_json::from_slice(&json).unwrap_or_default();
+        let result = f(&mut state);
+        self.file.rewind()?;
+        self.file.set_len(0)?;
+        self.file.write_all(&serde_json::to_vec(&state)?)?;
+        Ok(result)
+    }
+
+    fn lock(&self) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            if unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                return Ok(());
+            }
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(error).with_context(|| format!("error locking {}", self.file.path().display()));
+            }
+            if start.elapsed() >= LOCK_WAIT {
+                bail!("timed out waiting for the lock on {}", self.file.path().display());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateFile;
+    use std::{collections::BTreeMap, os::unix::io::AsRawFd, time::Instant};
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("new");
+        let mut state = StateFile::open(&dir, "state.json").unwrap();
+        let add = |state: &mut StateFile| state.update(|counts: &mut BTreeMap<String, u64>| {
+            *counts.entry("foo".to_owned()).or_default() += 1;
+            counts["foo"]
+        });
+        assert_eq!(add(&mut state).unwrap(), 1);
+        assert_eq!(add(&mut StateFile::open(&dir, "state.json").unwrap()).unwrap(), 2);
+
+        // Someone else holding the lock doesn't hang us.
+        let holder = std::fs::File::open(dir.join("state.json")).unwrap();
+        assert_eq!(unsafe { libc::flock(holder.as_raw_fd(), libc::LOCK_EX) }, 0);
+        let start = Instant::now();
+        let error = add(&mut state).unwrap_err().to_string();
+        assert!(error.contains("timed out"), "{}", error);
+        assert!(start.elapsed() < super::LOCK_WAIT * 2);
+        drop(holder);
+        assert_eq!(add(&mut state).unwrap(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+//! `set_storm_threshold()`: notice when an executable is crashing over and
+//! over, and log it once rather than leaving it to be spotted in a flood of
+//! identical messages.
+//!
+//! The times of each executable's crashes in the last minute are kept in a
+//! small JSON file in the lock directory, updated under an `flock()` like
+//! the sampling counts. That's a sliding window: each crash drops the times
+//! that are more than `WINDOW_SECS` older than it and counts what's left,
+//! including itself. A storm starts when that reaches the threshold, and ends
+//! at the first crash after it's dropped below half the threshold (or when a
+//! whole window passes with no crashes at all), so a rate that hovers around
+//! the threshold doesn't start a new storm every few seconds.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::state::StateFile;
+
+pub const STATE_FILE: &str = "storms.json";
+
+pub const WINDOW_SECS: u64 = 60;
+
+/// Most crash times kept per executable. Rates above this are reported as
+/// this.
+const MAX_TIMES: usize = 10000;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    /// Crash times in the last `WINDOW_SECS`, oldest first.
+    times: Vec<u64>,
+    /// Crashes since the current storm started, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    storm: Option<u64>,
+    /// When the last crash picked by `Storm::Storm::sample` was, in the
+    /// current storm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampled: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storm {
+    Calm,
+    /// In a storm of `rate` crashes a minute. `crashes` is how many there
+    /// were before this one since it started, so it's 0 for the crash that
+    /// started it. `sample` is set for the first crash of the storm and then
+    /// the first one at least `WINDOW_SECS` after the last one it was set
+    /// for, so there's one a minute whatever the rate.
+    Storm { rate: u64, crashes: u64, sample: bool },
+}
+
+/// Record a crash of `key` at `now` and see whether it's in a storm.
+pub fn record(state: &mut StateFile, key: &str, now: u64, threshold: u64) -> Result<Storm> {
+    // Corrupt state just forgets the recent crashes.
+    state.update(|histories: &mut BTreeMap<String, History>| {
+        for history in histories.values_mut() {
+            history.times.retain(|&time| time + WINDOW_SECS > now);
+        }
+        histories.retain(|_, history| !history.times.is_empty());
+
+        let history = histories.entry(key.to_owned()).or_default();
+        history.times.push(now);
+        if history.times.len() > MAX_TIMES {
+            history.times.remove(0);
+        }
+        update(history, threshold, now)
+    })
+}
+
+fn update(history: &mut History, threshold: u64, now: u64) -> Storm {
+    let rate = history.times.len() as u64;
+    history.storm = match history.storm {
+        Some(_) if rate * 2 < threshold => None,
+        Some(crashes) => Some(crashes + 1),
+        None if rate >= threshold => Some(0),
+        None => None,
+    };
+    let crashes = match history.storm {
+        Some(crashes) => crashes,
+        None => {
+            history.sampled = None;
+            return Storm::Calm;
+        }
+    };
+    let sample = crashes == 0 || history.sampled.is_none_or(|sampled| now >= sampled + WINDOW_SECS);
+    if sample {
+        history.sampled = Some(now);
+    }
+    Storm::Storm { rate, crashes, sample }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{record, Storm, STATE_FILE};
+    use crate::state::StateFile;
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = StateFile::open(dir.path(), STATE_FILE).unwrap();
+        let mut record = |key, now, threshold| record(&mut state, key, now, threshold).unwrap();
+        let storm = |rate, crashes, sample| Storm::Storm { rate, crashes, sample };
+        assert_eq!(record("/usr/bin/foo", 1000, 3), Storm::Calm);
+        assert_eq!(record("/usr/bin/foo", 1010, 3), Storm::Calm);
+        assert_eq!(record("/usr/bin/bar", 1010, 3), Storm::Calm);
+        assert_eq!(record("/usr/bin/foo", 1059, 3), storm(3, 0, true));
+        // 1000 has dropped out of the window.
+        assert_eq!(record("/usr/bin/foo", 1060, 3), storm(3, 1, false));
+        // Still a storm until the rate halves.
+        assert_eq!(record("/usr/bin/foo", 1115, 5), storm(3, 2, false));
+        assert_eq!(record("/usr/bin/foo", 1119, 5), storm(3, 3, true));
+        assert_eq!(record("/usr/bin/foo", 1175, 5), Storm::Calm);
+        assert_eq!(record("/usr/bin/foo", 1176, 5), Storm::Calm);
+
+        // A quiet minute forgets everything.
+        assert_eq!(record("/usr/bin/bar", 1300, 1), storm(1, 0, true));
+        assert_eq!(record("/usr/bin/bar", 1400, 1), storm(1, 0, true));
+    }
+}
@@ -2,9 +2,16 @@
 
 ulimit -c unlimited
 
-sudo sysctl -w kernel.core_pattern="|/home/vagrant/test_input/sellafield -u %u -p %p -t %t -E %E -c %c --config /home/vagrant/test_input/sellafield_config.rhai"
+sudo sysctl -w kernel.core_pattern="|/home/vagrant/test_input/sellafield -u %u -p %p -t %t -E %E -c %c -d %d -s %s --config /home/vagrant/test_input/sellafield_config.rhai"
 test_input/generate_core_dump
 echo "Abort finished"
 
 ls -ld test_output
 ls -l test_output
+
+# Copying test_output out directly fails with a permission error, so stage
+# copies in a plain vagrant-owned directory that transient can copy out.
+mkdir -p copy_out
+for f in test_output/*; do
+    install -m 644 "$f" copy_out/
+done
@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use std::process::Command;
 
 mod command_capture;
@@ -10,12 +10,12 @@ fn main() -> Result<()> {
 
     let (stdout, stderr) = run_vm()?;
 
-    // TODO: More robust checks.
-    if !stdout.contains("test_output") {
-        bail!("`test_output` not found in output");
-    }
-    if !stdout.contains("core.vagrant.generate_core_dump") {
-        bail!("`core.vagrant.generate_core_dump` not found in output");
+    check_core("../test_copy_out/core.vagrant.generate_core_dump")?;
+
+    // The copied out file doesn't keep its permissions so check those from
+    // the `ls` output.
+    if !stdout.contains("-r-----r--") {
+        bail!("core dump permissions are not 0o404");
     }
 
     eprintln!("Test succeeded.\n--- Stdout ---\n{}--- Stderr ---\n{}", stdout, stderr);
@@ -39,11 +39,25 @@ fn build_linux_release() -> Result<()> {
     Ok(())
 }
 
+fn check_core(path: &str) -> Result<()> {
+    let core = std::fs::read(path).with_context(|| format!("core dump not copied out of VM: {}", path))?;
+    if !core.starts_with(b"\x7fELF") {
+        bail!("{} is not an ELF core dump", path);
+    }
+    Ok(())
+}
+
 fn run_vm() -> Result<(String, String)> {
     std::fs::copy("../target/x86_64-unknown-linux-musl/release/sellafield", "../test_input/sellafield")?;
 
-    // Unfortunately `--copy-out-after /home/vagrant/test_output:test_output`
-    // gives a permission error that I'm not sure about but we can just scrape stdout.
+    // Remove results from any previous run so we don't check stale files.
+    match std::fs::remove_dir_all("../test_copy_out") {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    // `--copy-out-after /home/vagrant/test_output:test_output` gives a
+    // permission error, so `test.sh` stages the results in `copy_out`.
     let mut command = Command::new("transient");
     command
         .arg("--verbose")
@@ -53,6 +67,8 @@ fn run_vm() -> Result<(String, String)> {
         .arg("test_input:/home/vagrant/test_input")
         .arg("--ssh-command")
         .arg("/home/vagrant/test_input/test.sh")
+        .arg("--copy-out-after")
+        .arg("/home/vagrant/copy_out:test_copy_out")
         .arg("--")
         .arg("-m")
         .arg("1G")